@@ -1,4 +1,4 @@
-use crate::modules::error::{to_error_result, UssResult};
+use crate::modules::error::{to_error_result, UssError, UssResult};
 
 // 4096 (max item size) + 5 bytes (per libdeflater)
 const COMPRESSION_VEC_CAPACITY: usize = 4111;
@@ -11,10 +11,56 @@ pub struct CompressorCollectionNode {
 pub struct CompressorCollection {
     count: usize,
     first: Option<Box<CompressorCollectionNode>>,
+    level: libdeflater::CompressionLvl,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum CompressionProfile {
+    Fast,
+    Balanced,
+    #[default]
+    Max,
+    // libdeflate level, 0 to 12
+    Level(i32),
+}
+
+impl CompressionProfile {
+    // levels outside 0..=12 are an error, not clamped to the nearest one
+    pub fn validate(&self) -> UssResult<()> {
+        match self {
+            CompressionProfile::Level(level) if !(0..=12).contains(level) => Err(
+                UssError::StaticError("CompressionProfile::Level must be between 0 and 12"),
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn deflate_level(&self) -> libdeflater::CompressionLvl {
+        match self {
+            CompressionProfile::Fast => libdeflater::CompressionLvl::fastest(),
+            CompressionProfile::Balanced => libdeflater::CompressionLvl::default(),
+            CompressionProfile::Max => libdeflater::CompressionLvl::best(),
+            CompressionProfile::Level(level) => libdeflater::CompressionLvl::new(*level)
+                .unwrap_or(libdeflater::CompressionLvl::best()),
+        }
+    }
+
+    pub fn zstd_level(&self) -> i32 {
+        match self {
+            CompressionProfile::Fast => 3,
+            CompressionProfile::Balanced => 9,
+            CompressionProfile::Max => 19,
+            // scale 0..=12 onto zstd's 1..=19, clamping first so the product can't overflow
+            CompressionProfile::Level(level) => ((*level).clamp(0, 12) * 19 / 12).max(1),
+        }
+    }
 }
 
 pub fn alloc_compressor() -> libdeflater::Compressor {
-    let level = libdeflater::CompressionLvl::best();
+    alloc_compressor_with_level(libdeflater::CompressionLvl::best())
+}
+
+pub fn alloc_compressor_with_level(level: libdeflater::CompressionLvl) -> libdeflater::Compressor {
     let compressor = libdeflater::Compressor::new(level);
 
     return compressor;
@@ -22,9 +68,14 @@ pub fn alloc_compressor() -> libdeflater::Compressor {
 
 impl CompressorCollection {
     pub fn new() -> CompressorCollection {
+        CompressorCollection::with_profile(CompressionProfile::Max)
+    }
+
+    pub fn with_profile(profile: CompressionProfile) -> CompressorCollection {
         CompressorCollection {
             count: 0,
             first: None,
+            level: profile.deflate_level(),
         }
     }
 
@@ -33,7 +84,7 @@ impl CompressorCollection {
 
         match taken {
             None => {
-                return alloc_compressor();
+                return alloc_compressor_with_level(self.level);
             }
             Some(mut boxed) => {
                 self.first = boxed.next.take();
@@ -1,26 +1,77 @@
 use crate::modules::error::{to_error_result, UssResult};
 
-pub fn decompress(data: &[u8], outlen: usize) -> UssResult<Vec<u8>> {
-    let mut decompressor = libdeflater::Decompressor::new();
-    let mut out: Vec<u8> = Vec::with_capacity(outlen);
+pub struct DecompressorCollectionNode {
+    decompressor: libdeflater::Decompressor,
+    next: Option<Box<DecompressorCollectionNode>>,
+}
+
+pub struct DecompressorCollection {
+    count: usize,
+    first: Option<Box<DecompressorCollectionNode>>,
+}
 
-    unsafe {
-        // safe because we know the Vec's capacity
-        out.set_len(outlen);
+impl DecompressorCollection {
+    pub fn new() -> DecompressorCollection {
+        DecompressorCollection {
+            count: 0,
+            first: None,
+        }
     }
 
-    match libdeflater::Decompressor::deflate_decompress(&mut decompressor, data, out.as_mut_slice())
-    {
-        Ok(length) => {
-            if length < outlen {
-                unsafe {
-                    // safe because we're decreasing Vec size
-                    out.set_len(length);
-                }
+    pub fn pop(&mut self) -> libdeflater::Decompressor {
+        let taken = self.first.take();
+
+        match taken {
+            None => {
+                return libdeflater::Decompressor::new();
             }
+            Some(mut boxed) => {
+                self.first = boxed.next.take();
+                self.count -= 1;
 
-            Ok(out)
+                return boxed.decompressor;
+            }
         }
-        Err(err) => return to_error_result(err),
     }
+
+    pub fn push(&mut self, decompressor: libdeflater::Decompressor) -> &mut DecompressorCollection {
+        let node = DecompressorCollectionNode {
+            decompressor,
+            next: self.first.take(),
+        };
+
+        let boxed = Box::new(node);
+
+        self.first = Some(boxed);
+        self.count += 1;
+
+        return self;
+    }
+
+    pub fn decompress_into(&mut self, data: &[u8], out: &mut [u8]) -> UssResult<usize> {
+        let mut decompressor = self.pop();
+
+        let result = match libdeflater::Decompressor::deflate_decompress(&mut decompressor, data, out)
+        {
+            Ok(length) => Ok(length),
+            Err(err) => to_error_result(err),
+        };
+
+        self.push(decompressor);
+
+        return result;
+    }
+
+    pub fn decompress(&mut self, data: &[u8], outlen: usize) -> UssResult<Vec<u8>> {
+        let mut out: Vec<u8> = vec![0; outlen];
+        let length = self.decompress_into(data, out.as_mut_slice())?;
+
+        out.truncate(length);
+
+        Ok(out)
+    }
+}
+
+pub fn decompress(data: &[u8], outlen: usize) -> UssResult<Vec<u8>> {
+    return DecompressorCollection::new().decompress(data, outlen);
 }
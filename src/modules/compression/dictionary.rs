@@ -0,0 +1,71 @@
+use crate::modules::error::{to_error, UssError, UssResult};
+
+// zstd frame magic; a raw deflate stream can never start with these bytes
+pub const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+// the dictionary is stored as a single chunk, so it shares the max item size
+pub const DICTIONARY_MAX_SIZE: usize = 4096;
+
+pub struct Dictionary {
+    pub hash: [u8; 50],
+    data: Vec<u8>,
+    encoder: zstd::dict::EncoderDictionary<'static>,
+    decoder: zstd::dict::DecoderDictionary<'static>,
+}
+
+pub fn is_zstd_frame(data: &[u8]) -> bool {
+    data.starts_with(&ZSTD_MAGIC)
+}
+
+pub fn train(samples: &[Vec<u8>], max_size: usize) -> UssResult<Vec<u8>> {
+    if samples.is_empty() {
+        return Err(UssError::StaticError(
+            "dictionary::train: no samples to train on",
+        ));
+    }
+
+    zstd::dict::from_samples(samples, max_size).map_err(to_error)
+}
+
+impl Dictionary {
+    pub fn new(hash: [u8; 50], dictionary: &[u8], level: i32) -> Dictionary {
+        Dictionary {
+            hash,
+            data: dictionary.to_vec(),
+            encoder: zstd::dict::EncoderDictionary::copy(dictionary, level),
+            decoder: zstd::dict::DecoderDictionary::copy(dictionary),
+        }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn compress(&self, data: &[u8]) -> UssResult<Vec<u8>> {
+        let mut compressor =
+            zstd::bulk::Compressor::with_prepared_dictionary(&self.encoder).map_err(to_error)?;
+
+        compressor.compress(data).map_err(to_error)
+    }
+
+    pub fn compress_with_level(&self, data: &[u8], level: i32) -> UssResult<Vec<u8>> {
+        let mut compressor =
+            zstd::bulk::Compressor::with_dictionary(level, &self.data).map_err(to_error)?;
+
+        compressor.compress(data).map_err(to_error)
+    }
+
+    pub fn decompress(&self, data: &[u8], outlen: usize) -> UssResult<Vec<u8>> {
+        let mut decompressor =
+            zstd::bulk::Decompressor::with_prepared_dictionary(&self.decoder).map_err(to_error)?;
+
+        decompressor.decompress(data, outlen).map_err(to_error)
+    }
+
+    pub fn decompress_into(&self, data: &[u8], out: &mut [u8]) -> UssResult<usize> {
+        let mut decompressor =
+            zstd::bulk::Decompressor::with_prepared_dictionary(&self.decoder).map_err(to_error)?;
+
+        decompressor.decompress_to_buffer(data, out).map_err(to_error)
+    }
+}
@@ -1,6 +1,10 @@
 pub mod compressor;
 pub mod decompressor;
+pub mod dictionary;
 
 pub use compressor::compress;
+pub use compressor::CompressionProfile;
 pub use compressor::init_compressor_collection;
 pub use decompressor::decompress;
+pub use decompressor::DecompressorCollection;
+pub use dictionary::Dictionary;
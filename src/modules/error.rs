@@ -6,6 +6,8 @@ pub enum UssError {
     IoProblem,
     MmapProblem,
     MutexPoison,
+    // stored data does not match the hash it was requested by
+    Corruption([u8; 50]),
 }
 
 pub type UssResult<T> = Result<T, UssError>;
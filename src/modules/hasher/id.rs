@@ -0,0 +1,305 @@
+use super::ChunkHasher;
+use crate::modules::error::{UssError, UssResult};
+
+// Hash ids are 50 ASCII bytes, stored as they are in chunk headers, lake
+// indexes and serialized trees. There are two layouts:
+//
+//   legacy   the first 50 characters of base64(sha256 ^ blake3, checksum,
+//            u16 length), as returned by hash() and held by older lakes
+//   tagged   base64(digest [u8; 32], check [u8; 3]) in 47 characters, then
+//            TAG_MARK, the layout version and the algorithm code
+//
+// TAG_MARK isn't a base64 character, so the layouts can't be mistaken for
+// one another. The digest comes first, so tagged ids spread over shards
+// and directories by prefix just like legacy ones.
+//
+// Only 32-byte digests fit: chunk headers, lake indexes and trees all hold
+// ids as [u8; 50]. Longer digests would need a longer id throughout, and a
+// new lake layout, not just a new TAG_VERSION.
+pub const HASH_ID_LENGTH: usize = 50;
+pub const TAG_MARK: u8 = b'.';
+pub const TAG_VERSION: u8 = b'1';
+
+// base64 characters in a tagged id, and the index of its TAG_MARK
+pub(crate) const TAGGED_BASE64: usize = 47;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub enum HashAlgorithm {
+    // sha256 ^ blake3 in the legacy layout
+    #[default]
+    Legacy,
+    // plain blake3 in the tagged layout
+    Blake3,
+}
+
+impl HashAlgorithm {
+    fn code(self) -> u8 {
+        match self {
+            HashAlgorithm::Legacy => 0,
+            HashAlgorithm::Blake3 => b'b',
+        }
+    }
+
+    fn from_code(code: u8) -> Option<HashAlgorithm> {
+        match code {
+            b'b' => Some(HashAlgorithm::Blake3),
+            _ => None,
+        }
+    }
+
+    // value of a lake header's hash_algorithm; lakes from before it are
+    // zeroed, which reads as Legacy
+    pub fn to_header(self) -> u32 {
+        self.code() as u32
+    }
+
+    pub fn from_header(value: u32) -> Option<HashAlgorithm> {
+        match value {
+            0 => Some(HashAlgorithm::Legacy),
+            value => HashAlgorithm::from_code(u8::try_from(value).ok()?),
+        }
+    }
+
+    // as written in manifests
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Legacy => "legacy",
+            HashAlgorithm::Blake3 => "blake3",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<HashAlgorithm> {
+        match name {
+            "legacy" => Some(HashAlgorithm::Legacy),
+            "blake3" => Some(HashAlgorithm::Blake3),
+            _ => None,
+        }
+    }
+}
+
+// low 24 bits of the checksum, stored in tagged ids
+fn tag_check(digest: &[u8; 32]) -> [u8; 3] {
+    let check = super::checksum_u32(digest, 32).to_le_bytes();
+
+    [check[0], check[1], check[2]]
+}
+
+fn is_tagged(id: &[u8; 50]) -> bool {
+    id[TAGGED_BASE64] == TAG_MARK
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct HashId {
+    bytes: [u8; 50],
+}
+
+impl HashId {
+    pub fn of(algorithm: HashAlgorithm, data: &[u8]) -> HashId {
+        let mut hasher = ChunkHasher::new(algorithm);
+
+        hasher.update(data);
+        hasher.finalize_id()
+    }
+
+    // the legacy layout, cut at 50 characters
+    pub(super) fn legacy(xored: &[u8; 32], length: u16) -> HashId {
+        let mut raw = [0u8; 38];
+
+        raw[..32].copy_from_slice(xored);
+        raw[32..36].copy_from_slice(&super::checksum(xored, length as u32));
+        raw[36..].copy_from_slice(&length.to_le_bytes());
+
+        let encoded = crate::base64::encode(&raw);
+        let mut bytes = [0u8; 50];
+
+        bytes.copy_from_slice(&encoded[..HASH_ID_LENGTH]);
+
+        HashId { bytes }
+    }
+
+    // a tagged id; Legacy ids also carry the data length, so they can only
+    // be made by of(), ChunkHasher or parse()
+    pub fn from_digest(algorithm: HashAlgorithm, digest: &[u8; 32]) -> UssResult<HashId> {
+        match algorithm {
+            HashAlgorithm::Legacy => Err(UssError::StaticError(
+                "HashId::from_digest: legacy ids aren't made from a digest",
+            )),
+            algorithm => Ok(HashId::tagged(algorithm, digest)),
+        }
+    }
+
+    pub(super) fn tagged(algorithm: HashAlgorithm, digest: &[u8; 32]) -> HashId {
+        let mut raw = [0u8; 35];
+
+        raw[..32].copy_from_slice(digest);
+        raw[32..].copy_from_slice(&tag_check(digest));
+
+        let encoded = crate::base64::encode(&raw);
+        let mut bytes = [0u8; 50];
+
+        bytes[..TAGGED_BASE64].copy_from_slice(&encoded[..TAGGED_BASE64]);
+        bytes[TAGGED_BASE64] = TAG_MARK;
+        bytes[TAGGED_BASE64 + 1] = TAG_VERSION;
+        bytes[TAGGED_BASE64 + 2] = algorithm.code();
+
+        HashId { bytes }
+    }
+
+    // accepts either layout, checks the id's own checksum
+    pub fn parse(bytes: &[u8]) -> UssResult<HashId> {
+        let bytes: [u8; 50] = match bytes.try_into() {
+            Ok(bytes) => bytes,
+            Err(_) => return Err(UssError::StaticError("HashId: ids are 50 bytes long")),
+        };
+
+        if is_tagged(&bytes) {
+            if bytes[TAGGED_BASE64 + 1] != TAG_VERSION {
+                return Err(UssError::StaticError("HashId: unknown id version"));
+            }
+
+            if HashAlgorithm::from_code(bytes[TAGGED_BASE64 + 2]).is_none() {
+                return Err(UssError::StaticError("HashId: unknown hash algorithm"));
+            }
+        }
+
+        if !super::verify_hash_integrity(&bytes) {
+            return Err(UssError::StaticError("HashId: id checksum mismatch"));
+        }
+
+        Ok(HashId { bytes })
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 50] {
+        &self.bytes
+    }
+
+    // 0 for legacy ids
+    pub fn version(&self) -> u8 {
+        match is_tagged(&self.bytes) {
+            true => self.bytes[TAGGED_BASE64 + 1] - b'0',
+            false => 0,
+        }
+    }
+
+    pub fn algorithm(&self) -> HashAlgorithm {
+        algorithm_of(&self.bytes).unwrap_or_default()
+    }
+
+    pub fn digest(&self) -> [u8; 32] {
+        let length = match is_tagged(&self.bytes) {
+            true => TAGGED_BASE64,
+            false => HASH_ID_LENGTH,
+        };
+
+        let mut padded = [b'A'; 52];
+
+        padded[..length].copy_from_slice(&self.bytes[..length]);
+
+        let decoded = crate::base64::decode(&padded);
+        let mut digest = [0u8; 32];
+
+        digest.copy_from_slice(&decoded[..32]);
+
+        digest
+    }
+
+    pub fn verify(&self, data: &[u8]) -> bool {
+        verify(&self.bytes, data)
+    }
+}
+
+// None for tagged ids of an unknown version or algorithm
+pub fn algorithm_of(id: &[u8; 50]) -> Option<HashAlgorithm> {
+    if !is_tagged(id) {
+        return Some(HashAlgorithm::Legacy);
+    }
+
+    match id[TAGGED_BASE64 + 1] {
+        TAG_VERSION => HashAlgorithm::from_code(id[TAGGED_BASE64 + 2]),
+        _ => None,
+    }
+}
+
+pub fn hash_with(algorithm: HashAlgorithm, data: &[u8]) -> [u8; 50] {
+    *HashId::of(algorithm, data).as_bytes()
+}
+
+// re-hashes data with the algorithm the id names
+pub fn verify(id: &[u8; 50], data: &[u8]) -> bool {
+    match algorithm_of(id) {
+        Some(algorithm) => &hash_with(algorithm, data) == id,
+        None => false,
+    }
+}
+
+// checks the check bytes of a tagged id
+pub(super) fn verify_tagged_integrity(id: &[u8; 50]) -> bool {
+    if algorithm_of(id).is_none() {
+        return false;
+    }
+
+    let mut padded = [b'A'; 48];
+
+    padded[..TAGGED_BASE64].copy_from_slice(&id[..TAGGED_BASE64]);
+
+    let decoded = crate::base64::decode(&padded);
+    let mut digest = [0u8; 32];
+
+    digest.copy_from_slice(&decoded[..32]);
+
+    decoded[32..35] == tag_check(&digest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALGORITHMS: [HashAlgorithm; 2] = [HashAlgorithm::Legacy, HashAlgorithm::Blake3];
+
+    #[test]
+    fn round_trip() {
+        for algorithm in ALGORITHMS {
+            let id = HashId::of(algorithm, b"some data");
+
+            assert_eq!(HashId::parse(id.as_bytes()).unwrap(), id);
+            assert_eq!(id.algorithm(), algorithm);
+            assert_eq!(algorithm_of(id.as_bytes()), Some(algorithm));
+            assert!(id.verify(b"some data"));
+            assert!(!id.verify(b"other data"));
+            assert_eq!(HashAlgorithm::from_name(algorithm.name()), Some(algorithm));
+        }
+
+        let legacy = HashId::of(HashAlgorithm::Legacy, b"some data");
+
+        assert_eq!(legacy.as_bytes(), &crate::hasher::hash(b"some data"));
+        assert_eq!(legacy.version(), 0);
+    }
+
+    #[test]
+    fn tagged_ids_carry_their_digest() {
+        let digest = crate::hasher::blake3(b"some data");
+        let id = HashId::from_digest(HashAlgorithm::Blake3, &digest).unwrap();
+
+        assert_eq!(id, HashId::of(HashAlgorithm::Blake3, b"some data"));
+        assert_eq!(id.digest(), digest);
+        assert_eq!(id.version(), 1);
+        assert_eq!(id.as_bytes()[TAGGED_BASE64], TAG_MARK);
+        assert!(HashId::from_digest(HashAlgorithm::Legacy, &digest).is_err());
+    }
+
+    #[test]
+    fn parse_rejects_damaged_ids() {
+        let id = *HashId::of(HashAlgorithm::Blake3, b"some data").as_bytes();
+        let damaged = |index: usize, byte: u8| {
+            let mut bytes = id;
+
+            bytes[index] = byte;
+            bytes
+        };
+
+        assert!(HashId::parse(&id[..49]).is_err());
+        assert!(HashId::parse(&damaged(3, if id[3] == b'A' { b'B' } else { b'A' })).is_err());
+        assert!(HashId::parse(&damaged(TAGGED_BASE64 + 1, b'2')).is_err());
+        assert!(HashId::parse(&damaged(TAGGED_BASE64 + 2, b'z')).is_err());
+    }
+}
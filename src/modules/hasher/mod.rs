@@ -1,3 +1,9 @@
+pub mod id;
+pub mod stream;
+
+pub use id::*;
+pub use stream::*;
+
 use sha2::{Digest, Sha256};
 
 pub fn sha256(data: &[u8]) -> [u8; 32] {
@@ -42,36 +48,31 @@ pub fn checksum(data: &[u8], length: u32) -> [u8; 4] {
 }
 
 pub fn hash(data: &[u8]) -> [u8; 50] {
-    let length: u16 = data.len() as u16;
-    let shasum = sha256(data);
-    let blasum = blake3(data);
-    let xored = xor(shasum, blasum);
-    let checksum = checksum(&xored, length as u32);
-
-    let mut vec: Vec<u8> = Vec::with_capacity(38);
+    hash_with(HashAlgorithm::Legacy, data)
+}
 
-    vec.extend_from_slice(&xored);
-    vec.extend_from_slice(&checksum);
-    vec.extend_from_slice(&length.to_le_bytes());
+pub fn verify_hash_integrity(hash: &[u8; 50]) -> bool {
+    if hash[47] == id::TAG_MARK {
+        return id::verify_tagged_integrity(hash);
+    }
 
-    let encoded = super::base64::encode(&vec);
+    // hash() cuts the encoding at 50 chars, which only keeps the high nibble
+    // of the length's upper byte; pad so decode() doesn't drop that nibble
+    let mut padded = [b'A'; 52];
 
-    return encoded[..50]
-        .try_into()
-        .expect("hash() result failed into([u8; 50])");
-}
+    padded[..50].copy_from_slice(hash);
 
-pub fn verify_hash_integrity(hash: &[u8; 50]) -> bool {
-    let bytes = super::base64::decode(hash);
+    let bytes = super::base64::decode(&padded);
     let xored = &bytes[0..32];
-    let length = bytes[36] as u32 + ((bytes[37] as u32) << 8);
-    let checksum = checksum(xored, length);
 
-    for i in 0..4 {
-        if checksum[i] != bytes[i + 32] {
-            return false;
+    for low_nibble in 0..16 {
+        let length = bytes[36] as u32 + (((bytes[37] | low_nibble) as u32) << 8);
+        let checksum = checksum(xored, length);
+
+        if checksum == bytes[32..36] {
+            return true;
         }
     }
 
-    return true;
+    return false;
 }
@@ -0,0 +1,148 @@
+use super::{HashAlgorithm, HashId};
+use sha2::{Digest, Sha256};
+
+// Hashes data fed in pieces; finalize() gives the same id as hash_with()
+// over the concatenation. The length is counted in full, but the legacy
+// layout only keeps its low 16 bits, like hash() always did.
+#[derive(Clone)]
+pub struct ChunkHasher {
+    algorithm: HashAlgorithm,
+    // only fed for Legacy ids
+    sha256: Sha256,
+    blake3: blake3::Hasher,
+    length: u64,
+}
+
+impl Default for ChunkHasher {
+    fn default() -> Self {
+        ChunkHasher::new(HashAlgorithm::default())
+    }
+}
+
+impl ChunkHasher {
+    pub fn new(algorithm: HashAlgorithm) -> ChunkHasher {
+        ChunkHasher {
+            algorithm,
+            sha256: Sha256::new(),
+            blake3: blake3::Hasher::new(),
+            length: 0,
+        }
+    }
+
+    pub fn algorithm(&self) -> HashAlgorithm {
+        self.algorithm
+    }
+
+    // bytes fed so far
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+
+    pub fn update(&mut self, data: &[u8]) -> &mut ChunkHasher {
+        if self.algorithm == HashAlgorithm::Legacy {
+            self.sha256.update(data);
+        }
+
+        self.blake3.update(data);
+        self.length += data.len() as u64;
+
+        self
+    }
+
+    pub fn finalize_id(self) -> HashId {
+        let blake3 = *self.blake3.finalize().as_bytes();
+
+        match self.algorithm {
+            HashAlgorithm::Legacy => {
+                let xored = super::xor(self.sha256.finalize().into(), blake3);
+
+                HashId::legacy(&xored, self.length as u16)
+            }
+            algorithm => HashId::tagged(algorithm, &blake3),
+        }
+    }
+
+    pub fn finalize(self) -> [u8; 50] {
+        *self.finalize_id().as_bytes()
+    }
+}
+
+// lets writers hash with std::io::copy() or alongside another writer
+impl std::io::Write for ChunkHasher {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.update(buf);
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{blake3, checksum, hash_with, sha256, xor};
+    use super::*;
+
+    // hash() as it was before ChunkHasher, which legacy ids must keep matching
+    fn original_hash(data: &[u8]) -> [u8; 50] {
+        let length = data.len() as u16;
+        let xored = xor(sha256(data), blake3(data));
+        let mut raw = Vec::with_capacity(38);
+
+        raw.extend_from_slice(&xored);
+        raw.extend_from_slice(&checksum(&xored, length as u32));
+        raw.extend_from_slice(&length.to_le_bytes());
+
+        crate::base64::encode(&raw)[..50].try_into().unwrap()
+    }
+
+    fn data(length: usize) -> Vec<u8> {
+        (0..length).map(|i| (i * 31 % 251) as u8).collect()
+    }
+
+    #[test]
+    fn pieces_hash_like_the_concatenation() {
+        // 70000 bytes don't fit the u16 length that legacy ids keep
+        for length in [0, 1, 4096, 70000] {
+            let data = data(length);
+
+            for algorithm in [HashAlgorithm::Legacy, HashAlgorithm::Blake3] {
+                let mut hasher = ChunkHasher::new(algorithm);
+
+                for piece in data.chunks(1000) {
+                    hasher.update(piece);
+                }
+
+                assert_eq!(hasher.length(), length as u64);
+                assert_eq!(hasher.finalize(), hash_with(algorithm, &data));
+            }
+
+            assert_eq!(
+                hash_with(HashAlgorithm::Legacy, &data),
+                original_hash(&data)
+            );
+        }
+    }
+
+    #[test]
+    fn legacy_ids_keep_the_low_16_bits_of_the_length() {
+        let long = data(65536 + 10);
+        let id = HashId::of(HashAlgorithm::Legacy, &long);
+
+        assert_eq!(id.as_bytes(), &original_hash(&long));
+        assert!(crate::hasher::verify_hash_integrity(id.as_bytes()));
+        assert!(id.verify(&long));
+    }
+
+    #[test]
+    fn writes_feed_the_hasher() {
+        let data = data(10000);
+        let mut hasher = ChunkHasher::new(HashAlgorithm::Blake3);
+
+        std::io::copy(&mut data.as_slice(), &mut hasher).unwrap();
+
+        assert_eq!(hasher.finalize(), hash_with(HashAlgorithm::Blake3, &data));
+    }
+}
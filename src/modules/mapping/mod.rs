@@ -1,4 +1,6 @@
-use super::error::{to_error, UssResult};
+use super::error::{to_error, UssError, UssResult};
+use fs2::FileExt;
+use std::sync::atomic::{AtomicU32, Ordering};
 
 pub struct MemoryMapping {
     pub owned_ro: Option<std::sync::Arc<std::sync::Mutex<memmap::Mmap>>>,
@@ -12,10 +14,13 @@ impl MemoryMapping {
     }
 
     pub fn read_u32(&self, offset_in_u32_chunks: u32) -> u32 {
-        let offset = offset_in_u32_chunks << 2;
-        let bytes = self.get_ro_slice(offset as usize, 4);
+        let offset = (offset_in_u32_chunks as usize) << 2;
+        let bytes = self.get_ro_slice(offset, 4);
 
-        u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+        // index slots are written by DataLake::put() while other threads read
+        let slot = bytes.as_ptr() as *const AtomicU32;
+
+        u32::from_le(unsafe { (*slot).load(Ordering::Acquire) })
     }
 }
 
@@ -25,7 +30,12 @@ pub fn create_ro_mapping(file_path: &str) -> UssResult<MemoryMapping> {
         .open(file_path)
         .map_err(to_error)?;
 
-    let mmap = unsafe { memmap::MmapOptions::new().map(&file).map_err(to_error)? };
+    map_ro_file(&file)
+}
+
+// maps the whole of an open file, at its current length
+pub fn map_ro_file(file: &std::fs::File) -> UssResult<MemoryMapping> {
+    let mmap = unsafe { memmap::MmapOptions::new().map(file).map_err(to_error)? };
 
     let slice: &[u8] = unsafe { std::slice::from_raw_parts(mmap[..].as_ptr(), mmap.len()) };
 
@@ -60,3 +70,76 @@ pub fn create_rw_mapping(file_path: &str) -> UssResult<MemoryMapping> {
         roref: slice,
     })
 }
+
+// Locking protocol, with flocks held as long as the returned handles live:
+//
+//   writer   exclusive on <lake>.writer, so a second writer fails to open
+//            the lake; appends need nothing more, since chunks are written
+//            before data_next and the index slot that point at them
+//   readers  shared on the lake file, taken by every open lake, the
+//            writer's included
+//   rewrite  rewriting published bytes in place converts the writer's
+//            shared lock to exclusive, and fails while other lakes are open
+pub fn writer_lock_path(file_path: &str) -> String {
+    format!("{}.writer", file_path)
+}
+
+pub fn lock_writer(file_path: &str) -> UssResult<std::fs::File> {
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(writer_lock_path(file_path))
+        .map_err(to_error)?;
+
+    if file.try_lock_exclusive().is_err() {
+        return Err(UssError::DynamicError(format!(
+            "{} is locked by another writer",
+            file_path
+        )));
+    }
+
+    Ok(file)
+}
+
+// waits while another process rewrites the lake
+pub fn lock_shared(file_path: &str) -> UssResult<std::fs::File> {
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .open(file_path)
+        .map_err(to_error)?;
+
+    file.lock_shared().map_err(to_error)?;
+
+    Ok(file)
+}
+
+// exclusive for as long as it lives, shared again when dropped; holds a
+// duplicate of the lake's handle, which shares its flock
+pub struct RewriteLock {
+    file: std::fs::File,
+}
+
+impl RewriteLock {
+    pub fn new(shared: &std::fs::File) -> UssResult<RewriteLock> {
+        let file = shared.try_clone().map_err(to_error)?;
+
+        if file.try_lock_exclusive().is_err() {
+            // a failed conversion may have dropped the shared lock
+            file.lock_shared().map_err(to_error)?;
+
+            return Err(UssError::StaticError(
+                "lake is open elsewhere, can't rewrite it in place",
+            ));
+        }
+
+        Ok(RewriteLock { file })
+    }
+}
+
+impl Drop for RewriteLock {
+    fn drop(&mut self) {
+        let _ = self.file.lock_shared();
+    }
+}
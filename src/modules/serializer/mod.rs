@@ -1,19 +1,19 @@
 use crate::{store::*, *};
 use serde::{de::DeserializeOwned, Serialize};
 
-pub fn serialize<T: Serialize>(arg: &T, lake: &mut DataLake) -> UssResult<String> {
+pub fn serialize<T: Serialize, S: ChunkStore + ?Sized>(arg: &T, store: &S) -> UssResult<String> {
     let vec = bitcode::serialize(arg).map_err(to_error)?;
 
     Ok(unsafe {
         String::from_utf8_unchecked(if vec.len() < 36 {
             base64::encode(&vec)
         } else {
-            lake.put(&vec)?.header.hash.to_vec()
+            store.put(&vec)?.to_vec()
         })
     })
 }
 
-pub fn hash_to_bytes(arg: &[u8], lake: &mut DataLake) -> Vec<u8> {
+pub fn hash_to_bytes<S: ChunkStore + ?Sized>(arg: &[u8], store: &S) -> Vec<u8> {
     if arg.len() < 50 {
         return base64::decode(arg);
     }
@@ -25,10 +25,8 @@ pub fn hash_to_bytes(arg: &[u8], lake: &mut DataLake) -> Vec<u8> {
         };
 
         if let Some(hash) = hash {
-            if let Some(chunk) = lake.get(&hash) {
-                if let Some(data) = chunk.read().ok() {
-                    return data.to_vec();
-                }
+            if let Ok(Some(data)) = store.get(&hash) {
+                return data;
             }
         }
     }
@@ -36,6 +34,78 @@ pub fn hash_to_bytes(arg: &[u8], lake: &mut DataLake) -> Vec<u8> {
     return arg.to_vec();
 }
 
-pub fn deserialize<T: DeserializeOwned>(arg: &[u8], lake: &mut DataLake) -> UssResult<T> {
-    bitcode::deserialize(&hash_to_bytes(arg, lake)).map_err(to_error)
+pub fn deserialize<T: DeserializeOwned, S: ChunkStore + ?Sized>(
+    arg: &[u8],
+    store: &S,
+) -> UssResult<T> {
+    if let Ok(hash) = <&[u8; 50]>::try_from(arg) {
+        let mut buffer = Vec::new();
+
+        match store.get_with(hash, &mut buffer) {
+            Ok(Some(data)) => return bitcode::deserialize(data).map_err(to_error),
+            // only a hash that isn't stored may be an inline value; data
+            // that can't be read must not be mistaken for one
+            Ok(None) => (),
+            Err(err) => return Err(err),
+        }
+
+        return bitcode::deserialize(arg).map_err(to_error);
+    }
+
+    bitcode::deserialize(&hash_to_bytes(arg, store)).map_err(to_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // fails every read, like a lake with bit rot or a missing key
+    struct FailingStore;
+
+    impl ChunkStore for FailingStore {
+        fn get(&self, _hash: &[u8; 50]) -> UssResult<Option<Vec<u8>>> {
+            Err(UssError::StaticError("FailingStore: read failed"))
+        }
+
+        fn put(&self, data: &[u8]) -> UssResult<[u8; 50]> {
+            Ok(crate::hasher::hash(data))
+        }
+
+        fn has(&self, _hash: &[u8; 50]) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let store = MemoryStore::new();
+        let short = String::from("short");
+        let long = "a longer value that is stored as a chunk".repeat(4);
+
+        let inline = serialize(&short, &store).unwrap();
+        let hashed = serialize(&long, &store).unwrap();
+
+        assert!(inline.len() < 50);
+        assert_eq!(hashed.len(), 50);
+        assert_eq!(store.len(), 1);
+        assert_eq!(
+            deserialize::<String, _>(inline.as_bytes(), &store).unwrap(),
+            short
+        );
+        assert_eq!(
+            deserialize::<String, _>(hashed.as_bytes(), &store).unwrap(),
+            long
+        );
+    }
+
+    #[test]
+    fn read_errors_propagate() {
+        let long = "a longer value that is stored as a chunk".repeat(4);
+        let hashed = serialize(&long, &MemoryStore::new()).unwrap();
+
+        assert!(matches!(
+            deserialize::<String, _>(hashed.as_bytes(), &FailingStore),
+            Err(UssError::StaticError("FailingStore: read failed"))
+        ));
+    }
 }
@@ -0,0 +1,573 @@
+use super::*;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashSet, VecDeque},
+    io::{BufReader, BufWriter, Read},
+};
+
+// Archive layout, all integers little-endian:
+//
+//   header   b"DLArchiv", version u32
+//   record   1u8, hash [u8; 50], uncompressed_length u16,
+//            compressed_length u16, payload
+//   trailer  0u8, chunks u64, roots u32, root hashes [u8; 50] each,
+//            sha256 of everything before it [u8; 32]
+//
+// A payload is deflate output, or the data itself when both lengths are
+// equal, as in a lake without dictionary or key.
+pub const ARCHIVE_MAGIC: &[u8; 8] = b"DLArchiv";
+pub const ARCHIVE_VERSION: u32 = 1;
+
+const RECORD_CHUNK: u8 = 1;
+const RECORD_TRAILER: u8 = 0;
+
+pub struct ArchiveRecord {
+    pub hash: [u8; 50],
+    pub uncompressed_length: u16,
+    pub payload: Vec<u8>,
+}
+
+impl ArchiveRecord {
+    // decompresses the payload and checks it against the hash
+    pub fn decode(&self) -> UssResult<Vec<u8>> {
+        let data = if self.payload.len() == self.uncompressed_length as usize {
+            self.payload.clone()
+        } else {
+            crate::compression::decompressor::decompress(
+                &self.payload,
+                self.uncompressed_length as usize,
+            )?
+        };
+
+        if !crate::hasher::verify(&self.hash, &data) {
+            return Err(UssError::Corruption(self.hash));
+        }
+
+        Ok(data)
+    }
+}
+
+pub struct ArchiveTrailer {
+    pub chunks: u64,
+    pub roots: Vec<[u8; 50]>,
+}
+
+pub struct ArchiveWriter<W: Write> {
+    out: W,
+    digest: Sha256,
+    chunks: u64,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    pub fn new(out: W) -> UssResult<ArchiveWriter<W>> {
+        let mut writer = ArchiveWriter {
+            out,
+            digest: Sha256::new(),
+            chunks: 0,
+        };
+
+        writer.write(ARCHIVE_MAGIC)?;
+        writer.write(&ARCHIVE_VERSION.to_le_bytes())?;
+
+        Ok(writer)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> UssResult<()> {
+        self.digest.update(bytes);
+        self.out.write_all(bytes).map_err(to_error)
+    }
+
+    // payload as described above; it isn't decoded or checked here
+    pub fn write_record(
+        &mut self,
+        hash: &[u8; 50],
+        uncompressed_length: u16,
+        payload: &[u8],
+    ) -> UssResult<()> {
+        if payload.len() > uncompressed_length as usize {
+            return Err(UssError::StaticError(
+                "ArchiveWriter: payload is longer than the data",
+            ));
+        }
+
+        self.write(&[RECORD_CHUNK])?;
+        self.write(hash)?;
+        self.write(&uncompressed_length.to_le_bytes())?;
+        self.write(&(payload.len() as u16).to_le_bytes())?;
+        self.write(payload)?;
+        self.chunks += 1;
+
+        Ok(())
+    }
+
+    pub fn write_data(&mut self, data: &[u8]) -> UssResult<[u8; 50]> {
+        let hash = crate::hasher::hash(data);
+        let payload = encode_payload(crate::compression::compressor::compress(data)?, data, true);
+
+        self.write_record(&hash, data.len() as u16, &payload)?;
+
+        Ok(hash)
+    }
+
+    pub fn finish(mut self, roots: &[[u8; 50]]) -> UssResult<W> {
+        self.write(&[RECORD_TRAILER])?;
+        self.write(&self.chunks.to_le_bytes())?;
+        self.write(&(roots.len() as u32).to_le_bytes())?;
+
+        for root in roots.iter() {
+            self.write(root)?;
+        }
+
+        let checksum: [u8; 32] = self.digest.finalize_reset().into();
+
+        self.out.write_all(&checksum).map_err(to_error)?;
+        self.out.flush().map_err(to_error)?;
+
+        Ok(self.out)
+    }
+}
+
+pub struct ArchiveReader<R: Read> {
+    input: R,
+    digest: Sha256,
+    chunks: u64,
+    trailer: Option<ArchiveTrailer>,
+}
+
+impl<R: Read> ArchiveReader<R> {
+    pub fn new(input: R) -> UssResult<ArchiveReader<R>> {
+        let mut reader = ArchiveReader {
+            input,
+            digest: Sha256::new(),
+            chunks: 0,
+            trailer: None,
+        };
+
+        let mut header = [0u8; 12];
+
+        reader.read(&mut header)?;
+
+        if &header[..8] != ARCHIVE_MAGIC {
+            return Err(UssError::StaticError("ArchiveReader: not a chunk archive"));
+        }
+
+        if header[8..] != ARCHIVE_VERSION.to_le_bytes() {
+            return Err(UssError::StaticError(
+                "ArchiveReader: unknown archive version",
+            ));
+        }
+
+        Ok(reader)
+    }
+
+    fn read(&mut self, buffer: &mut [u8]) -> UssResult<()> {
+        self.input.read_exact(buffer).map_err(to_error)?;
+        self.digest.update(&buffer[..]);
+
+        Ok(())
+    }
+
+    fn read_u16(&mut self) -> UssResult<u16> {
+        let mut bytes = [0u8; 2];
+
+        self.read(&mut bytes)?;
+
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    // None once the trailer was read and its checksum verified
+    pub fn next_record(&mut self) -> UssResult<Option<ArchiveRecord>> {
+        if self.trailer.is_some() {
+            return Ok(None);
+        }
+
+        let mut tag = [0u8; 1];
+
+        self.read(&mut tag)?;
+
+        match tag[0] {
+            RECORD_CHUNK => {}
+            RECORD_TRAILER => {
+                self.read_trailer()?;
+                return Ok(None);
+            }
+            _ => return Err(UssError::StaticError("ArchiveReader: unknown record")),
+        }
+
+        let mut hash = [0u8; 50];
+
+        self.read(&mut hash)?;
+
+        let uncompressed_length = self.read_u16()?;
+        let mut payload = vec![0; self.read_u16()? as usize];
+
+        self.read(&mut payload)?;
+        self.chunks += 1;
+
+        Ok(Some(ArchiveRecord {
+            hash,
+            uncompressed_length,
+            payload,
+        }))
+    }
+
+    fn read_trailer(&mut self) -> UssResult<()> {
+        let mut chunks = [0u8; 8];
+        let mut count = [0u8; 4];
+
+        self.read(&mut chunks)?;
+        self.read(&mut count)?;
+
+        let mut roots = Vec::new();
+
+        for _ in 0..u32::from_le_bytes(count) {
+            let mut root = [0u8; 50];
+
+            self.read(&mut root)?;
+            roots.push(root);
+        }
+
+        let expected: [u8; 32] = self.digest.finalize_reset().into();
+        let mut checksum = [0u8; 32];
+
+        self.input.read_exact(&mut checksum).map_err(to_error)?;
+
+        if checksum != expected {
+            return Err(UssError::StaticError(
+                "ArchiveReader: archive checksum mismatch",
+            ));
+        }
+
+        if u64::from_le_bytes(chunks) != self.chunks {
+            return Err(UssError::StaticError(
+                "ArchiveReader: trailer chunk count mismatch",
+            ));
+        }
+
+        self.trailer = Some(ArchiveTrailer {
+            chunks: self.chunks,
+            roots,
+        });
+
+        Ok(())
+    }
+
+    pub fn trailer(&self) -> Option<&ArchiveTrailer> {
+        self.trailer.as_ref()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExportSelection {
+    All,
+    // chunks referenced, directly or through other chunks, by the roots
+    Reachable,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ExportReport {
+    pub chunks: usize,
+    pub payload_bytes: u64,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ImportReport {
+    pub records: usize,
+    pub imported: usize,
+    // records for chunks the lake already held
+    pub duplicates: usize,
+    pub roots: Vec<String>,
+    // roots still not in the lake after the import
+    pub missing_roots: Vec<String>,
+}
+
+fn is_hash_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'~' || c == b'_'
+}
+
+// 50-character runs of hash characters, or tagged ids, that carry a valid
+// hash checksum; that's how trees reference their children inside
+// serialized chunks
+pub fn references(data: &[u8]) -> Vec<[u8; 50]> {
+    let mut found = Vec::new();
+    let mut run = 0;
+
+    for (i, &c) in data.iter().enumerate() {
+        if c == crate::hasher::TAG_MARK && run >= 47 && i + 2 < data.len() {
+            if let Ok(hash) = <[u8; 50]>::try_from(&data[i - 47..i + 3]) {
+                if crate::hasher::verify_hash_integrity(&hash) {
+                    found.push(hash);
+                }
+            }
+        }
+
+        run = if is_hash_char(c) { run + 1 } else { 0 };
+
+        if run < 50 {
+            continue;
+        }
+
+        if let Ok(hash) = <[u8; 50]>::try_from(&data[i + 1 - 50..=i]) {
+            if crate::hasher::verify_hash_integrity(&hash) {
+                found.push(hash);
+            }
+        }
+    }
+
+    found
+}
+
+impl DataLake {
+    // Hashes of the roots and every stored chunk they reference, in
+    // breadth-first order. Fails if a root isn't stored.
+    pub fn reachable(&self, roots: &[[u8; 50]]) -> UssResult<Vec<[u8; 50]>> {
+        let mut seen: HashSet<[u8; 50]> = HashSet::new();
+        let mut queue: VecDeque<[u8; 50]> = VecDeque::new();
+        let mut order = Vec::new();
+
+        for root in roots.iter() {
+            if self.get(root).is_none() {
+                return Err(UssError::DynamicError(format!(
+                    "reachable: root {} is not stored",
+                    fsck::hash_string(root)
+                )));
+            }
+
+            if seen.insert(*root) {
+                queue.push_back(*root);
+            }
+        }
+
+        let mut buffer = Vec::new();
+
+        while let Some(hash) = queue.pop_front() {
+            let data = match self.get_with(&hash, &mut buffer)? {
+                Some(data) => data,
+                None => continue,
+            };
+
+            for reference in references(data) {
+                if !seen.contains(&reference) && self.get(&reference).is_some() {
+                    seen.insert(reference);
+                    queue.push_back(reference);
+                }
+            }
+
+            order.push(hash);
+        }
+
+        Ok(order)
+    }
+
+    // The chunk's stored payload when it is already portable. Lakes from
+    // before raw payloads may store deflate output as long as the data or
+    // longer, which an archive would take for raw data or refuse.
+    pub(super) fn archive_payload(&self, chunk: &DataChunk) -> UssResult<Vec<u8>> {
+        let stored = chunk.read_compressed()?;
+        let legacy_length =
+            !chunk.raw_payloads && stored.len() >= chunk.header.uncompressed_length as usize;
+
+        if !chunk.encrypted && !dictionary::is_zstd_frame(stored) && !legacy_length {
+            return Ok(stored.to_vec());
+        }
+
+        let mut buffer = Vec::new();
+        let data = self.read(chunk, &mut buffer)?;
+        let compressed = self.with_compressor(|compressors| compressors.compress(data))?;
+
+        Ok(encode_payload(compressed, data, true))
+    }
+
+    // Writes the selected chunks to out as an archive, with roots in its
+    // trailer.
+    pub fn export<W: Write>(
+        &self,
+        out: W,
+        roots: &[[u8; 50]],
+        selection: ExportSelection,
+    ) -> UssResult<ExportReport> {
+        let mut report = ExportReport::default();
+        let mut writer = ArchiveWriter::new(BufWriter::new(out))?;
+
+        let mut write = |chunk: DataChunk| -> UssResult<()> {
+            let payload = self.archive_payload(&chunk)?;
+
+            writer.write_record(
+                &chunk.header.hash,
+                chunk.header.uncompressed_length,
+                &payload,
+            )?;
+            report.chunks += 1;
+            report.payload_bytes += payload.len() as u64;
+
+            Ok(())
+        };
+
+        match selection {
+            ExportSelection::All => {
+                for chunk in self.iter() {
+                    write(chunk)?;
+                }
+            }
+            ExportSelection::Reachable => {
+                for hash in self.reachable(roots)? {
+                    if let Some(chunk) = self.get(&hash) {
+                        write(chunk)?;
+                    }
+                }
+            }
+        }
+
+        writer.finish(roots)?;
+
+        Ok(report)
+    }
+
+    // Stores every chunk of the archive that the lake doesn't hold yet.
+    // Chunks are written in a transaction that is only committed once the
+    // trailer's checksum matched, so a damaged archive imports nothing.
+    pub fn import<R: Read>(&self, input: R) -> UssResult<ImportReport> {
+        let mut report = ImportReport::default();
+        let mut reader = ArchiveReader::new(BufReader::new(input))?;
+        let mut transaction = self.begin()?;
+
+        while let Some(record) = reader.next_record()? {
+            report.records += 1;
+
+            if transaction.get(&record.hash).is_some() {
+                report.duplicates += 1;
+                continue;
+            }
+
+            transaction.put_payload(&record.hash, record.uncompressed_length, &record.payload)?;
+            report.imported += 1;
+        }
+
+        transaction.commit()?;
+
+        if let Some(trailer) = reader.trailer() {
+            for root in trailer.roots.iter() {
+                report.roots.push(fsck::hash_string(root));
+
+                if self.get(root).is_none() {
+                    report.missing_roots.push(fsck::hash_string(root));
+                }
+            }
+        }
+
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        store::tests::{equal_length_data, noise},
+        tree::Node,
+    };
+
+    fn export_all(lake: &DataLake, roots: &[[u8; 50]]) -> Vec<u8> {
+        let mut archive = Vec::new();
+
+        lake.export(&mut archive, roots, ExportSelection::All)
+            .unwrap();
+
+        archive
+    }
+
+    #[test]
+    fn round_trip() {
+        let source = DataLake::create(&test_path("archive-source.lake"), 1 << 20).unwrap();
+        let items: Vec<Vec<u8>> = vec![
+            b"short".to_vec(),
+            "compressible ".repeat(100).into_bytes(),
+            noise(1000, 0),
+        ];
+        let hashes: Vec<[u8; 50]> = items
+            .iter()
+            .map(|item| source.put(item).unwrap().header.hash)
+            .collect();
+
+        let archive = export_all(&source, &hashes[..1]);
+        let target = DataLake::create(&test_path("archive-target.lake"), 1 << 20).unwrap();
+        let report = target.import(archive.as_slice()).unwrap();
+
+        assert_eq!((report.records, report.imported), (3, 3));
+        assert!(report.missing_roots.is_empty());
+
+        for (hash, item) in hashes.iter().zip(items.iter()) {
+            assert_eq!(target.get(hash).unwrap().read().unwrap(), *item);
+        }
+
+        assert_eq!(target.import(archive.as_slice()).unwrap().duplicates, 3);
+    }
+
+    #[test]
+    fn legacy_lakes_export_incompressible_chunks() {
+        let mut source = DataLake::create(&test_path("archive-legacy.lake"), 1 << 20).unwrap();
+
+        source.header_mut().format = 0;
+
+        // deflate output longer than the data, and exactly as long
+        let items = [noise(1000, 0), equal_length_data()];
+
+        for item in items.iter() {
+            let chunk = source.put(item).unwrap();
+
+            assert!(chunk.read_compressed().unwrap().len() >= item.len());
+        }
+
+        let archive = export_all(&source, &[]);
+        let target = DataLake::create(&test_path("archive-legacy-target.lake"), 1 << 20).unwrap();
+
+        assert_eq!(target.import(archive.as_slice()).unwrap().imported, 2);
+
+        for item in items.iter() {
+            let hash = crate::hasher::hash(item);
+
+            assert_eq!(target.get(&hash).unwrap().read().unwrap(), *item);
+        }
+    }
+
+    #[test]
+    fn damaged_archives_import_nothing() {
+        let source = DataLake::create(&test_path("archive-damaged.lake"), 1 << 20).unwrap();
+
+        source.put(b"first chunk").unwrap();
+        source.put(b"second chunk").unwrap();
+
+        let mut archive = export_all(&source, &[]);
+        let last = archive.len() - 1;
+
+        archive[last] ^= 1;
+
+        let target = DataLake::create(&test_path("archive-damaged-target.lake"), 1 << 20).unwrap();
+
+        assert!(target.import(archive.as_slice()).is_err());
+        assert!(target.is_empty());
+        assert!(target.fsck().unwrap().is_clean());
+    }
+
+    #[test]
+    fn trees_reference_their_chunks() {
+        let lake = Arc::new(DataLake::create(&test_path("archive-tree.lake"), 1 << 20).unwrap());
+        let store: Arc<dyn ChunkStore> = lake.clone();
+        let mut node = Node::<String, String>::new(store);
+
+        for i in 0..4 {
+            node = node
+                .set(format!("{} key", i), format!("value {} ", i).repeat(20))
+                .unwrap();
+        }
+
+        let root = node.hash().unwrap();
+        let root: [u8; 50] = root.as_bytes().try_into().unwrap();
+        let reachable = lake.reachable(&[root]).unwrap();
+
+        // the root, four leaves and their values
+        assert_eq!(reachable.len(), lake.len());
+        assert_eq!(reachable.len(), 9);
+    }
+}
@@ -0,0 +1,182 @@
+use super::*;
+use std::{collections::HashMap, sync::atomic::AtomicUsize};
+
+enum Encoded {
+    Stored(DataChunk),
+    Chunk(DataChunkHeader, Vec<u8>),
+}
+
+impl DataLake {
+    fn worker_count(&self, jobs: usize) -> usize {
+        let workers = match self.workers {
+            0 => std::thread::available_parallelism().map_or(1, |count| count.get()),
+            workers => workers,
+        };
+
+        workers.clamp(1, std::cmp::max(jobs, 1))
+    }
+
+    fn encode_item(&self, data: &[u8]) -> UssResult<Encoded> {
+        let hash = self.hash_of(data);
+
+        if let Some(chunk) = self.get(&hash) {
+            return Ok(Encoded::Stored(chunk));
+        }
+
+        let (header, payload) = self.encode_chunk(hash, data)?;
+
+        Ok(Encoded::Chunk(header, payload))
+    }
+
+    // hashes and compresses items on worker threads, results in input order
+    fn encode_many(&self, items: &[&[u8]]) -> UssResult<Vec<Encoded>> {
+        let next = AtomicUsize::new(0);
+        let workers = self.worker_count(items.len());
+        let mut results: Vec<Option<UssResult<Encoded>>> = items.iter().map(|_| None).collect();
+
+        let finished = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..workers)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut done = Vec::new();
+
+                        loop {
+                            let index = next.fetch_add(1, Ordering::Relaxed);
+
+                            if index >= items.len() {
+                                return done;
+                            }
+
+                            done.push((index, self.encode_item(items[index])));
+                        }
+                    })
+                })
+                .collect();
+
+            handles
+                .into_iter()
+                .map(|handle| handle.join())
+                .collect::<Vec<_>>()
+        });
+
+        for done in finished {
+            let done = match done {
+                Ok(done) => done,
+                Err(_) => return Err(UssError::StaticError("DataLake::put_many: worker panicked")),
+            };
+
+            for (index, result) in done {
+                results[index] = Some(result);
+            }
+        }
+
+        results
+            .into_iter()
+            .map(|result| result.unwrap_or(Err(UssError::UnknownError)))
+            .collect()
+    }
+
+    // Like put() for each item, but compression runs in parallel on up to
+    // DataLakeOptions::workers threads; chunks are appended in input order.
+    pub fn put_many(&self, items: &[&[u8]]) -> UssResult<Vec<DataChunk>> {
+        let encoded = self.encode_many(items)?;
+        let mut chunks = Vec::with_capacity(encoded.len());
+
+        let appending = encoded
+            .iter()
+            .any(|encoded| matches!(encoded, Encoded::Chunk(_, _)));
+
+        if appending && self.readonly {
+            return Err(UssError::StaticError(
+                "Must not call put_many() on readonly lake.",
+            ));
+        }
+
+        let _writer = match appending {
+            true => Some(self.lock_appends()?),
+            false => None,
+        };
+
+        let mut appended: HashMap<[u8; 50], DataChunk> = HashMap::new();
+
+        for encoded in encoded {
+            let (header, payload) = match encoded {
+                Encoded::Stored(chunk) => {
+                    chunks.push(chunk);
+                    continue;
+                }
+                Encoded::Chunk(header, payload) => (header, payload),
+            };
+
+            // repeated within the batch, or stored by another writer since
+            let existing = match appended.get(&header.hash) {
+                Some(chunk) => Some(chunk.clone()),
+                None => self.get(&header.hash),
+            };
+
+            let chunk = match existing {
+                Some(chunk) => chunk,
+                None => self.append_chunk(&mut *self.lock_map()?, header, &payload)?,
+            };
+
+            appended.insert(header.hash, chunk.clone());
+            chunks.push(chunk);
+        }
+
+        Ok(chunks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn put_many_matches_put() {
+        let path = test_path("batch.lake");
+        let lake = DataLake::create(&path, 1 << 20).unwrap();
+        let items: Vec<Vec<u8>> = (0..50)
+            .map(|i| {
+                format!("item {} ", i % 40)
+                    .repeat(i % 40 % 7 + 1)
+                    .into_bytes()
+            })
+            .collect();
+        let slices: Vec<&[u8]> = items.iter().map(|item| item.as_slice()).collect();
+
+        lake.put(slices[3]).unwrap();
+
+        let chunks = lake.put_many(&slices).unwrap();
+
+        assert_eq!(lake.len(), 40);
+
+        for (chunk, item) in chunks.iter().zip(items.iter()) {
+            assert_eq!(chunk.header.hash, crate::hasher::hash(item));
+            assert_eq!(&chunk.read().unwrap(), item);
+        }
+    }
+
+    #[test]
+    fn concurrent_puts() {
+        let path = test_path("batch-threads.lake");
+        let lake = DataLake::create(&path, 1 << 22).unwrap();
+
+        std::thread::scope(|scope| {
+            for thread in 0..4 {
+                let lake = &lake;
+
+                scope.spawn(move || {
+                    for i in 0..200 {
+                        // every thread stores the shared items too
+                        lake.put(format!("shared {}", i).as_bytes()).unwrap();
+                        lake.put(format!("own {} {}", thread, i).as_bytes())
+                            .unwrap();
+                    }
+                });
+            }
+        });
+
+        assert_eq!(lake.len(), 1000);
+        assert!(lake.fsck().unwrap().is_clean());
+    }
+}
@@ -0,0 +1,441 @@
+use super::DataChunk;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+const CACHE_SHARDS: usize = 16;
+
+// Limits are split across CACHE_SHARDS shards, each getting a share rounded
+// up, so a shard can start evicting while the cache as a whole is below them,
+// and the total can exceed them by up to CACHE_SHARDS - 1.
+#[derive(Clone, Copy, Debug)]
+pub struct CacheOptions {
+    // 0 disables the cache
+    pub max_entries: usize,
+    // counts cached payloads plus a fixed cost per entry
+    pub max_bytes: usize,
+    // also keep decompressed payloads of compressed chunks
+    pub cache_payloads: bool,
+}
+
+impl Default for CacheOptions {
+    fn default() -> Self {
+        CacheOptions {
+            max_entries: 1 << 16,
+            max_bytes: 64 << 20,
+            cache_payloads: false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub entries: usize,
+    pub bytes: usize,
+}
+
+impl CacheStats {
+    pub fn hit_rate(&self) -> f64 {
+        let lookups = self.hits + self.misses;
+
+        if lookups == 0 {
+            return 0.0;
+        }
+
+        self.hits as f64 / lookups as f64
+    }
+}
+
+struct CacheEntry {
+    hash: [u8; 50],
+    chunk: DataChunk,
+    payload: Option<Arc<[u8]>>,
+    // CLOCK reference bit, set on every hit
+    referenced: bool,
+}
+
+impl CacheEntry {
+    fn cost(&self) -> usize {
+        std::mem::size_of::<CacheEntry>() + self.payload.as_ref().map_or(0, |p| p.len())
+    }
+}
+
+struct CacheShard {
+    entries: Vec<CacheEntry>,
+    slots: HashMap<[u8; 50], usize>,
+    hand: usize,
+    bytes: usize,
+}
+
+impl CacheShard {
+    fn new() -> CacheShard {
+        CacheShard {
+            entries: Vec::new(),
+            slots: HashMap::new(),
+            hand: 0,
+            bytes: 0,
+        }
+    }
+
+    fn get(&mut self, hash: &[u8; 50]) -> Option<&mut CacheEntry> {
+        let slot = *self.slots.get(hash)?;
+        let entry = &mut self.entries[slot];
+
+        entry.referenced = true;
+
+        Some(entry)
+    }
+
+    // sweeps the clock hand until it finds an entry that wasn't hit since
+    // the last sweep, and drops it
+    fn evict_one(&mut self) -> bool {
+        if self.entries.is_empty() {
+            return false;
+        }
+
+        loop {
+            if self.hand >= self.entries.len() {
+                self.hand = 0;
+            }
+
+            let entry = &mut self.entries[self.hand];
+
+            if entry.referenced {
+                entry.referenced = false;
+                self.hand += 1;
+                continue;
+            }
+
+            self.remove(self.hand);
+
+            return true;
+        }
+    }
+
+    fn remove(&mut self, slot: usize) -> CacheEntry {
+        let entry = self.entries.swap_remove(slot);
+
+        self.slots.remove(&entry.hash);
+        self.bytes -= entry.cost();
+
+        if let Some(moved) = self.entries.get(slot) {
+            self.slots.insert(moved.hash, slot);
+        }
+
+        entry
+    }
+
+    // returns the number of evicted entries
+    fn make_room(&mut self, entries: usize, bytes: usize, options: &CacheOptions) -> u64 {
+        let mut evictions = 0;
+
+        while self.entries.len() + entries > options.max_entries
+            || self.bytes + bytes > options.max_bytes
+        {
+            if !self.evict_one() {
+                break;
+            }
+
+            evictions += 1;
+        }
+
+        evictions
+    }
+}
+
+// Sharded CLOCK cache of chunk handles and, optionally, decoded payloads.
+pub struct ChunkCache {
+    shards: Vec<Mutex<CacheShard>>,
+    // per-shard limits
+    options: CacheOptions,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl ChunkCache {
+    pub fn new(options: CacheOptions) -> ChunkCache {
+        ChunkCache {
+            shards: (0..CACHE_SHARDS)
+                .map(|_| Mutex::new(CacheShard::new()))
+                .collect(),
+            options: CacheOptions {
+                max_entries: options.max_entries.div_ceil(CACHE_SHARDS),
+                max_bytes: options.max_bytes.div_ceil(CACHE_SHARDS),
+                cache_payloads: options.cache_payloads,
+            },
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    fn shard(&self, hash: &[u8; 50]) -> &Mutex<CacheShard> {
+        let checksum = crate::hasher::checksum_u32(hash, 50) as usize;
+
+        &self.shards[checksum % CACHE_SHARDS]
+    }
+
+    pub fn get(&self, hash: &[u8; 50]) -> Option<DataChunk> {
+        let chunk = match self.shard(hash).lock() {
+            Ok(mut shard) => shard.get(hash).map(|entry| entry.chunk.clone()),
+            Err(_) => None,
+        };
+
+        match chunk {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+
+        chunk
+    }
+
+    // doesn't count as a hit, nor keep the entry from being evicted
+    pub fn contains(&self, hash: &[u8; 50]) -> bool {
+        match self.shard(hash).lock() {
+            Ok(shard) => shard.slots.contains_key(hash),
+            Err(_) => false,
+        }
+    }
+
+    pub fn insert(&self, chunk: &DataChunk) {
+        if self.options.max_entries == 0 {
+            return;
+        }
+
+        let hash = chunk.header.hash;
+
+        let mut shard = match self.shard(&hash).lock() {
+            Ok(shard) => shard,
+            Err(_) => return,
+        };
+
+        if shard.slots.contains_key(&hash) {
+            return;
+        }
+
+        let entry = CacheEntry {
+            hash,
+            chunk: chunk.clone(),
+            payload: None,
+            referenced: false,
+        };
+
+        self.push(&mut shard, entry);
+    }
+
+    fn push(&self, shard: &mut CacheShard, entry: CacheEntry) {
+        let cost = entry.cost();
+
+        // would never fit, so don't evict anything for it
+        if cost > self.options.max_bytes {
+            return;
+        }
+
+        let evictions = shard.make_room(1, cost, &self.options);
+
+        self.evictions.fetch_add(evictions, Ordering::Relaxed);
+
+        let slot = shard.entries.len();
+
+        shard.slots.insert(entry.hash, slot);
+        shard.entries.push(entry);
+        shard.bytes += cost;
+    }
+
+    pub fn payload(&self, hash: &[u8; 50]) -> Option<Arc<[u8]>> {
+        if !self.options.cache_payloads {
+            return None;
+        }
+
+        match self.shard(hash).lock() {
+            Ok(mut shard) => shard.get(hash)?.payload.clone(),
+            Err(_) => None,
+        }
+    }
+
+    // attaches a decoded payload to the chunk's entry, if it is still cached
+    pub fn insert_payload(&self, hash: &[u8; 50], payload: &[u8]) {
+        if !self.options.cache_payloads {
+            return;
+        }
+
+        let mut shard = match self.shard(hash).lock() {
+            Ok(shard) => shard,
+            Err(_) => return,
+        };
+
+        let slot = match shard.slots.get(hash) {
+            Some(&slot) => slot,
+            None => return,
+        };
+
+        if shard.entries[slot].payload.is_some() {
+            return;
+        }
+
+        // taken out while making room, so it can't evict itself
+        let mut entry = shard.remove(slot);
+
+        entry.payload = Some(Arc::from(payload));
+        entry.referenced = true;
+
+        if entry.cost() > self.options.max_bytes {
+            entry.payload = None;
+        }
+
+        self.push(&mut shard, entry);
+    }
+
+    // drops the chunk's handle and payload, e.g. after it was rewritten
+    pub fn remove(&self, hash: &[u8; 50]) {
+        if let Ok(mut shard) = self.shard(hash).lock() {
+            if let Some(&slot) = shard.slots.get(hash) {
+                shard.remove(slot);
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        for shard in self.shards.iter_mut() {
+            if let Ok(shard) = shard.get_mut() {
+                *shard = CacheShard::new();
+            }
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let mut stats = CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            ..Default::default()
+        };
+
+        for shard in self.shards.iter() {
+            if let Ok(shard) = shard.lock() {
+                stats.entries += shard.entries.len();
+                stats.bytes += shard.bytes;
+            }
+        }
+
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{test_path, DataLake};
+
+    const ENTRY: usize = std::mem::size_of::<CacheEntry>();
+
+    fn shard_of(hash: &[u8; 50]) -> usize {
+        crate::hasher::checksum_u32(hash, 50) as usize % CACHE_SHARDS
+    }
+
+    // chunks that all land in the same shard, so per-shard limits apply to them
+    fn one_shard_chunks(name: &str, count: usize) -> Vec<DataChunk> {
+        let lake = DataLake::create(&test_path(name), 1 << 20).unwrap();
+        let mut chunks: Vec<DataChunk> = Vec::new();
+
+        for i in 0.. {
+            let chunk = lake.put(format!("cached chunk {}", i).as_bytes()).unwrap();
+
+            if chunks.is_empty() || shard_of(&chunk.header.hash) == shard_of(&chunks[0].header.hash)
+            {
+                chunks.push(chunk);
+            }
+
+            if chunks.len() == count {
+                break;
+            }
+        }
+
+        chunks
+    }
+
+    fn per_shard(max_entries: usize, max_bytes: usize) -> ChunkCache {
+        ChunkCache::new(CacheOptions {
+            max_entries: max_entries * CACHE_SHARDS,
+            max_bytes: max_bytes * CACHE_SHARDS,
+            cache_payloads: true,
+        })
+    }
+
+    #[test]
+    fn clock_evicts_entries_not_hit_since_the_last_sweep() {
+        let chunks = one_shard_chunks("cache-clock.lake", 4);
+        let hashes: Vec<[u8; 50]> = chunks.iter().map(|chunk| chunk.header.hash).collect();
+        let cache = per_shard(3, 1 << 20);
+
+        for chunk in &chunks[..3] {
+            cache.insert(chunk);
+        }
+
+        assert!(cache.get(&hashes[0]).is_some());
+        assert!(cache.get(&hashes[3]).is_none());
+
+        cache.insert(&chunks[3]);
+
+        // the hand passes the hit entry and takes the next one
+        assert!(cache.contains(&hashes[0]));
+        assert!(!cache.contains(&hashes[1]));
+        assert!(cache.contains(&hashes[2]));
+        assert!(cache.contains(&hashes[3]));
+
+        let stats = cache.stats();
+
+        assert_eq!((stats.hits, stats.misses, stats.evictions), (1, 1, 1));
+        assert_eq!(stats.entries, 3);
+        assert_eq!(stats.hit_rate(), 0.5);
+    }
+
+    #[test]
+    fn byte_limit_counts_payloads_and_skips_oversized_ones() {
+        let chunks = one_shard_chunks("cache-bytes.lake", 3);
+        let hashes: Vec<[u8; 50]> = chunks.iter().map(|chunk| chunk.header.hash).collect();
+        let cache = per_shard(16, 2 * ENTRY + 150);
+
+        cache.insert(&chunks[0]);
+        cache.insert(&chunks[1]);
+        cache.insert_payload(&hashes[0], &[1; 100]);
+
+        assert_eq!(cache.stats().bytes, 2 * ENTRY + 100);
+        assert_eq!(cache.stats().evictions, 0);
+
+        // over the byte limit, so the first entry has to go
+        cache.insert_payload(&hashes[1], &[2; 100]);
+
+        assert!(!cache.contains(&hashes[0]));
+        assert_eq!(cache.payload(&hashes[1]).unwrap().len(), 100);
+        assert_eq!(cache.stats().bytes, ENTRY + 100);
+        assert_eq!(cache.stats().evictions, 1);
+
+        // a payload that could never fit is dropped, the entry and others stay
+        cache.insert(&chunks[2]);
+        cache.insert_payload(&hashes[2], &[3; 1000]);
+
+        assert!(cache.contains(&hashes[1]));
+        assert!(cache.contains(&hashes[2]));
+        assert!(cache.payload(&hashes[2]).is_none());
+        assert_eq!(cache.stats().bytes, 2 * ENTRY + 100);
+        assert_eq!(cache.stats().evictions, 1);
+
+        // and so is an entry larger than the whole shard
+        let tiny = per_shard(16, ENTRY - 1);
+
+        tiny.insert(&chunks[0]);
+
+        assert!(!tiny.contains(&hashes[0]));
+        assert_eq!(tiny.stats().evictions, 0);
+    }
+}
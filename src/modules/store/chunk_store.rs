@@ -0,0 +1,74 @@
+use super::*;
+
+// Content-addressed chunk storage, keyed by the hash of the data; stores
+// pick the algorithm, hasher::verify() checks a hash of any algorithm.
+pub trait ChunkStore: Send + Sync {
+    // None if the hash isn't stored
+    fn get(&self, hash: &[u8; 50]) -> UssResult<Option<Vec<u8>>>;
+
+    // stores data unless it is already present, returns its hash
+    fn put(&self, data: &[u8]) -> UssResult<[u8; 50]>;
+
+    fn has(&self, hash: &[u8; 50]) -> bool;
+
+    // like get(), but may borrow the data from the store instead of copying
+    fn get_with<'a>(
+        &'a self,
+        hash: &[u8; 50],
+        buffer: &'a mut Vec<u8>,
+    ) -> UssResult<Option<&'a [u8]>> {
+        match self.get(hash)? {
+            Some(data) => {
+                *buffer = data;
+                Ok(Some(buffer.as_slice()))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl ChunkStore for DataLake {
+    fn get(&self, hash: &[u8; 50]) -> UssResult<Option<Vec<u8>>> {
+        let mut buffer = Vec::new();
+
+        Ok(DataLake::get_with(self, hash, &mut buffer)?.map(|data| data.to_vec()))
+    }
+
+    fn put(&self, data: &[u8]) -> UssResult<[u8; 50]> {
+        Ok(DataLake::put(self, data)?.header.hash)
+    }
+
+    fn has(&self, hash: &[u8; 50]) -> bool {
+        DataLake::get(self, hash).is_some()
+    }
+
+    fn get_with<'a>(
+        &'a self,
+        hash: &[u8; 50],
+        buffer: &'a mut Vec<u8>,
+    ) -> UssResult<Option<&'a [u8]>> {
+        DataLake::get_with(self, hash, buffer)
+    }
+}
+
+impl<S: ChunkStore + ?Sized> ChunkStore for Arc<S> {
+    fn get(&self, hash: &[u8; 50]) -> UssResult<Option<Vec<u8>>> {
+        self.as_ref().get(hash)
+    }
+
+    fn put(&self, data: &[u8]) -> UssResult<[u8; 50]> {
+        self.as_ref().put(data)
+    }
+
+    fn has(&self, hash: &[u8; 50]) -> bool {
+        self.as_ref().has(hash)
+    }
+
+    fn get_with<'a>(
+        &'a self,
+        hash: &[u8; 50],
+        buffer: &'a mut Vec<u8>,
+    ) -> UssResult<Option<&'a [u8]>> {
+        self.as_ref().get_with(hash, buffer)
+    }
+}
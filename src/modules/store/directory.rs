@@ -0,0 +1,137 @@
+use super::chunk_store::ChunkStore;
+use crate::hasher::{TAGGED_BASE64, TAG_MARK};
+use crate::modules::error::{to_error, UssError, UssResult};
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+// One file per chunk, at <root>/<first two hash characters>/<hash>. Hashes
+// are case-sensitive, so the root must be on a case-sensitive filesystem.
+pub struct DirectoryStore {
+    root: PathBuf,
+    // re-hash chunk data on every read and fail with UssError::Corruption
+    verify: bool,
+    // makes temporary file names unique within the process
+    writes: AtomicU64,
+}
+
+impl DirectoryStore {
+    pub fn open<P: AsRef<Path>>(root: P) -> UssResult<DirectoryStore> {
+        std::fs::create_dir_all(root.as_ref()).map_err(to_error)?;
+
+        Ok(DirectoryStore {
+            root: root.as_ref().to_path_buf(),
+            verify: false,
+            writes: AtomicU64::new(0),
+        })
+    }
+
+    pub fn set_verify(&mut self, verify: bool) {
+        self.verify = verify;
+    }
+
+    // Hashes come from callers and serialized trees, so anything but an id
+    // made of base64 characters, with TAG_MARK where tagged ids keep it, is
+    // refused rather than joined into a path.
+    fn path(&self, hash: &[u8; 50]) -> UssResult<PathBuf> {
+        let valid = hash.iter().enumerate().all(|(i, byte)| {
+            crate::base64::ALPHABET.contains(byte) || (i == TAGGED_BASE64 && *byte == TAG_MARK)
+        });
+
+        let name = match std::str::from_utf8(hash) {
+            Ok(name) if valid => name,
+            _ => return Err(UssError::StaticError("DirectoryStore: invalid hash")),
+        };
+
+        Ok(self.root.join(&name[..2]).join(name))
+    }
+}
+
+impl ChunkStore for DirectoryStore {
+    fn get(&self, hash: &[u8; 50]) -> UssResult<Option<Vec<u8>>> {
+        let data = match std::fs::read(self.path(hash)?) {
+            Ok(data) => data,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(to_error(err)),
+        };
+
+        if self.verify && !crate::hasher::verify(hash, &data) {
+            return Err(UssError::Corruption(*hash));
+        }
+
+        Ok(Some(data))
+    }
+
+    fn put(&self, data: &[u8]) -> UssResult<[u8; 50]> {
+        let hash = crate::hasher::hash(data);
+        let path = self.path(&hash)?;
+
+        if path.exists() {
+            return Ok(hash);
+        }
+
+        let dir = match path.parent() {
+            Some(dir) => dir,
+            None => return Err(UssError::StaticError("DirectoryStore: invalid path")),
+        };
+
+        std::fs::create_dir_all(dir).map_err(to_error)?;
+
+        // written under a temporary name, so readers never see partial files
+        let temp = dir.join(format!(
+            ".{}.{}.{}.tmp",
+            String::from_utf8_lossy(&hash),
+            std::process::id(),
+            self.writes.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        std::fs::write(&temp, data).map_err(to_error)?;
+        std::fs::rename(&temp, &path).map_err(to_error)?;
+
+        Ok(hash)
+    }
+
+    fn has(&self, hash: &[u8; 50]) -> bool {
+        match self.path(hash) {
+            Ok(path) => path.exists(),
+            Err(_) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut store = DirectoryStore::open(crate::store::test_path("directory")).unwrap();
+        let hash = store.put(b"stored in a file").unwrap();
+
+        store.set_verify(true);
+
+        assert!(store.has(&hash));
+        assert_eq!(store.get(&hash).unwrap().unwrap(), b"stored in a file");
+        assert!(store
+            .get(&crate::hasher::hash(b"missing"))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn rejects_invalid_hashes() {
+        let store = DirectoryStore::open(crate::store::test_path("directory-invalid")).unwrap();
+        let mut traversal = crate::hasher::hash(b"outside");
+        let mut binary = traversal;
+
+        traversal[..3].copy_from_slice(b"../");
+        binary[10] = 0xff;
+
+        for hash in [traversal, binary] {
+            assert!(!store.has(&hash));
+            assert!(store.get(&hash).is_err());
+        }
+    }
+}
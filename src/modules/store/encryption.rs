@@ -0,0 +1,137 @@
+use crate::modules::error::{to_error, UssError, UssResult};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    XChaCha20Poly1305, XNonce,
+};
+
+pub const NONCE_SIZE: usize = 24;
+pub const TAG_SIZE: usize = 16;
+
+// bytes added to every encrypted payload
+pub const OVERHEAD: usize = NONCE_SIZE + TAG_SIZE;
+
+const KEY_CHECK_CONTEXT: &[u8] = b"DataLake key check";
+
+pub struct LakeKey {
+    key: [u8; 32],
+}
+
+impl LakeKey {
+    pub fn new(key: [u8; 32]) -> LakeKey {
+        LakeKey { key }
+    }
+
+    // stored in the lake header to detect a wrong key at load time
+    pub fn check_value(&self) -> [u8; 32] {
+        *blake3::keyed_hash(&self.key, KEY_CHECK_CONTEXT).as_bytes()
+    }
+
+    // convergent: equal content gets equal keys, so deduplication keeps working,
+    // while the lake key keeps the content hash alone from decrypting anything
+    fn chunk_key(&self, hash: &[u8; 50]) -> [u8; 32] {
+        *blake3::keyed_hash(&self.key, hash).as_bytes()
+    }
+
+    // returns nonce || ciphertext || tag
+    pub fn encrypt(&self, hash: &[u8; 50], payload: &[u8]) -> UssResult<Vec<u8>> {
+        let key = self.chunk_key(hash);
+        let cipher = XChaCha20Poly1305::new(&key.into());
+
+        // the nonce is derived from the payload, since recompress() may
+        // encrypt a different payload under the same key
+        let nonce_source = blake3::keyed_hash(&key, payload);
+        let nonce = XNonce::from_slice(&nonce_source.as_bytes()[..NONCE_SIZE]);
+
+        let ciphertext = cipher.encrypt(nonce, payload).map_err(to_error)?;
+
+        let mut out = Vec::with_capacity(NONCE_SIZE + ciphertext.len());
+
+        out.extend_from_slice(nonce);
+        out.extend_from_slice(&ciphertext);
+
+        Ok(out)
+    }
+
+    pub fn decrypt(&self, hash: &[u8; 50], data: &[u8]) -> UssResult<Vec<u8>> {
+        if data.len() < OVERHEAD {
+            return Err(UssError::StaticError(
+                "LakeKey::decrypt: payload is too short",
+            ));
+        }
+
+        let key = self.chunk_key(hash);
+        let cipher = XChaCha20Poly1305::new(&key.into());
+        let nonce = XNonce::from_slice(&data[..NONCE_SIZE]);
+
+        cipher.decrypt(nonce, &data[NONCE_SIZE..]).map_err(to_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{test_path, tests::overhead_length_data, DataLake, DataLakeOptions};
+
+    fn options(key: [u8; 32]) -> DataLakeOptions {
+        DataLakeOptions {
+            key: Some(key),
+            ..Default::default()
+        }
+    }
+
+    // data whose encrypted payload is exactly as long as the data
+    fn equal_length_data() -> Vec<u8> {
+        overhead_length_data(OVERHEAD)
+    }
+
+    #[test]
+    fn payload_round_trip() {
+        let key = LakeKey::new([5; 32]);
+        let hash = crate::hasher::hash(b"payload");
+        let encrypted = key.encrypt(&hash, b"payload").unwrap();
+
+        assert_eq!(encrypted.len(), b"payload".len() + OVERHEAD);
+        assert_eq!(key.decrypt(&hash, &encrypted).unwrap(), b"payload");
+        assert!(LakeKey::new([6; 32]).decrypt(&hash, &encrypted).is_err());
+    }
+
+    #[test]
+    fn lake_round_trip() {
+        let path = test_path("encrypted.lake");
+        let data = equal_length_data();
+        let hash = crate::hasher::hash(&data);
+
+        {
+            let lake = DataLake::create_with_options(&path, 1 << 20, options([5; 32])).unwrap();
+            let chunk = lake.put(&data).unwrap();
+
+            assert_eq!(chunk.header.compressed_length as usize, data.len());
+        }
+
+        let lake = DataLake::load_with_options(&path, true, options([5; 32])).unwrap();
+
+        assert_eq!(lake.get(&hash).unwrap().read().unwrap(), data);
+        assert!(DataLake::load_with_options(&path, true, options([6; 32])).is_err());
+    }
+
+    #[test]
+    fn reads_without_key_fail() {
+        let path = test_path("encrypted-no-key.lake");
+        let data = equal_length_data();
+        let hash = crate::hasher::hash(&data);
+
+        DataLake::create_with_options(&path, 1 << 20, options([5; 32]))
+            .unwrap()
+            .put(&data)
+            .unwrap();
+
+        let lake = DataLake::load(&path, true).unwrap();
+        let chunk = lake.get(&hash).unwrap();
+        let mut buffer = Vec::new();
+
+        assert!(!chunk.is_raw());
+        assert!(chunk.read().is_err());
+        assert!(lake.read(&chunk, &mut buffer).is_err());
+        assert!(lake.get_with(&hash, &mut buffer).is_err());
+    }
+}
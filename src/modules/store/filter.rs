@@ -0,0 +1,204 @@
+use super::*;
+use std::sync::atomic::AtomicU64;
+
+// ~1% false positives at 10 bits per chunk
+const BITS_PER_CHUNK: usize = 10;
+const PROBES: u32 = 7;
+
+// In-memory Bloom filter over the hashes in the data region. It can have
+// false positives but no false negatives, so a miss skips the index probe.
+pub struct HashFilter {
+    bits: Vec<AtomicU64>,
+    // data region up to this unit has been added
+    scanned: Mutex<u32>,
+}
+
+impl HashFilter {
+    pub fn new(capacity: usize, data_offset: u32) -> HashFilter {
+        let words = std::cmp::max(1, (capacity * BITS_PER_CHUNK).div_ceil(64));
+
+        HashFilter {
+            bits: (0..words).map(|_| AtomicU64::new(0)).collect(),
+            scanned: Mutex::new(data_offset),
+        }
+    }
+
+    fn positions(&self, hash: &[u8; 50]) -> impl Iterator<Item = usize> {
+        let bits = (self.bits.len() * 64) as u64;
+        let h1 = crate::hasher::checksum_u32(&hash[..25], 25) as u64;
+        let h2 = crate::hasher::checksum_u32(&hash[25..], 25) as u64 | 1;
+
+        (0..PROBES as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % bits) as usize)
+    }
+
+    pub fn insert(&self, hash: &[u8; 50]) {
+        for position in self.positions(hash) {
+            self.bits[position >> 6].fetch_or(1 << (position & 63), Ordering::Relaxed);
+        }
+    }
+
+    pub fn may_contain(&self, hash: &[u8; 50]) -> bool {
+        self.positions(hash).all(|position| {
+            self.bits[position >> 6].load(Ordering::Relaxed) & (1 << (position & 63)) != 0
+        })
+    }
+}
+
+impl DataLake {
+    // Adds chunks stored since the last call, e.g. by a writer in another
+    // process. Returns whether anything was added.
+    pub(super) fn filter_catch_up(&self) -> UssResult<bool> {
+        let filter = match &self.filter {
+            Some(filter) => filter,
+            None => return Ok(false),
+        };
+
+        let mut scanned = filter.scanned.lock().map_err(|_| UssError::MutexPoison)?;
+        let data_next = std::cmp::min(self.data_next(), self.mapped_units());
+        let mut offset = *scanned;
+
+        if offset >= data_next {
+            return Ok(false);
+        }
+
+        while offset < data_next {
+            let header = read_header(&self.data, offset);
+
+            if header.hash[0] == 0 {
+                offset += 1;
+                continue;
+            }
+
+            filter.insert(&header.hash);
+            offset += header.units();
+        }
+
+        *scanned = offset;
+
+        Ok(true)
+    }
+
+    // called by put() once the chunk at offset is published
+    pub(super) fn filter_insert(&self, hash: &[u8; 50], offset: u32, units: u32) {
+        let filter = match &self.filter {
+            Some(filter) => filter,
+            None => return,
+        };
+
+        filter.insert(hash);
+
+        if let Ok(mut scanned) = filter.scanned.lock() {
+            if *scanned == offset {
+                *scanned = offset + units;
+            }
+        }
+    }
+
+    // false only if the hash is certainly not stored
+    pub fn may_contain(&self, hash: &[u8; 50]) -> bool {
+        let filter = match &self.filter {
+            Some(filter) => filter,
+            None => return true,
+        };
+
+        if filter.may_contain(hash) {
+            return true;
+        }
+
+        match self.filter_catch_up() {
+            Ok(true) => filter.may_contain(hash),
+            Ok(false) => false,
+            Err(_) => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_filter() -> DataLakeOptions {
+        DataLakeOptions {
+            filter: true,
+            ..Default::default()
+        }
+    }
+
+    fn put_all(lake: &DataLake, range: std::ops::Range<usize>) -> Vec<[u8; 50]> {
+        range
+            .map(|i| {
+                lake.put(format!("filtered chunk {}", i).as_bytes())
+                    .unwrap()
+                    .header
+                    .hash
+            })
+            .collect()
+    }
+
+    #[test]
+    fn no_false_negatives_after_put_and_commit() {
+        let path = test_path("filter-put.lake");
+        let lake = DataLake::create_with_options(&path, 1 << 20, with_filter()).unwrap();
+        let mut hashes = put_all(&lake, 0..100);
+        let mut transaction = lake.begin().unwrap();
+
+        for i in 100..200 {
+            let chunk = transaction
+                .put(format!("filtered chunk {}", i).as_bytes())
+                .unwrap();
+
+            hashes.push(chunk.header.hash);
+        }
+
+        transaction.commit().unwrap();
+
+        for hash in &hashes {
+            assert!(lake.may_contain(hash));
+            assert!(lake.get(hash).is_some());
+        }
+
+        let missing = (0..100)
+            .filter(|i| !lake.may_contain(&crate::hasher::hash(format!("absent {}", i).as_bytes())))
+            .count();
+
+        assert!(missing > 90);
+    }
+
+    #[test]
+    fn catches_up_after_another_handle_appends() {
+        let path = test_path("filter-catch-up.lake");
+        let writer = DataLake::create(&path, 1 << 20).unwrap();
+        let before = put_all(&writer, 0..10);
+        let reader = DataLake::load_with_options(&path, true, with_filter()).unwrap();
+        let after = put_all(&writer, 10..50);
+
+        for hash in before.iter().chain(&after) {
+            assert!(reader.may_contain(hash));
+            assert!(reader.get(hash).is_some());
+        }
+
+        let scanned = *reader.filter.as_ref().unwrap().scanned.lock().unwrap();
+
+        assert_eq!(scanned, writer.data_next());
+    }
+
+    #[test]
+    fn reset_after_repair() {
+        let path = test_path("filter-repair.lake");
+        let mut lake = DataLake::create_with_options(&path, 1 << 20, with_filter()).unwrap();
+        let mut hashes = put_all(&lake, 0..50);
+
+        lake.repair().unwrap();
+
+        let scanned = *lake.filter.as_ref().unwrap().scanned.lock().unwrap();
+
+        assert_eq!(scanned, lake.data_next());
+
+        hashes.extend(put_all(&lake, 50..60));
+
+        for hash in &hashes {
+            assert!(lake.may_contain(hash));
+            assert!(lake.get(hash).is_some());
+        }
+    }
+}
@@ -0,0 +1,223 @@
+use super::*;
+use serde::Serialize;
+use std::collections::HashSet;
+
+#[derive(Debug, Serialize)]
+pub struct FsckChunk {
+    pub offset: u32,
+    pub hash: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FsckCorruptChunk {
+    pub offset: u32,
+    pub hash: String,
+    pub reason: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FsckSlot {
+    pub slot: u32,
+    pub offset: u32,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct FsckReport {
+    pub chunks: usize,
+    pub index_slots: usize,
+    // false for encrypted lakes loaded without a key
+    pub payloads_checked: bool,
+    // header fields that are out of range
+    pub header_errors: Vec<String>,
+    pub corrupt: Vec<FsckCorruptChunk>,
+    // chunks the index does not lead to
+    pub orphaned: Vec<FsckChunk>,
+    // chunks whose hash is already stored at a lower offset
+    pub duplicates: Vec<FsckChunk>,
+    // old copies of healed chunks; not an error
+    pub superseded: Vec<FsckChunk>,
+    // index slots that don't point at the start of a chunk
+    pub dangling_slots: Vec<FsckSlot>,
+    // index slots pointing at a chunk another slot already points at
+    pub duplicate_slots: Vec<FsckSlot>,
+    // index slots pointing past data_next, left by a commit that crashed;
+    // cleared when a writer opens the lake
+    pub unpublished_slots: Vec<FsckSlot>,
+}
+
+impl FsckReport {
+    pub fn is_clean(&self) -> bool {
+        self.header_errors.is_empty()
+            && self.corrupt.is_empty()
+            && self.orphaned.is_empty()
+            && self.duplicates.is_empty()
+            && self.dangling_slots.is_empty()
+            && self.duplicate_slots.is_empty()
+            && self.unpublished_slots.is_empty()
+    }
+}
+
+pub(super) fn hash_string(hash: &[u8; 50]) -> String {
+    String::from_utf8_lossy(hash).into_owned()
+}
+
+impl DataLake {
+    fn check_header(&self, report: &mut FsckReport) {
+        let header = self.header();
+        let data_next = self.data_next();
+        let mapped_units = self.mapped_units();
+
+        if &header.magic != b"DataLake" {
+            report.header_errors.push("bad magic".to_owned());
+        }
+
+        if header.file_size != self.data.roref.len() as u64 {
+            report.header_errors.push(format!(
+                "file_size {} does not match mapped size {}",
+                header.file_size,
+                self.data.roref.len()
+            ));
+        }
+
+        if header.index_max > header.data_offset << 6 {
+            report.header_errors.push(format!(
+                "index_max {} overlaps data_offset {}",
+                header.index_max, header.data_offset
+            ));
+        }
+
+        if index::IndexFormat::from_header(header.index_format).is_none() {
+            report
+                .header_errors
+                .push(format!("unknown index_format {}", header.index_format));
+        }
+
+        if data_next < header.data_offset || data_next > mapped_units {
+            report.header_errors.push(format!(
+                "data_next {} outside of data region {}..{}",
+                data_next, header.data_offset, mapped_units
+            ));
+        }
+    }
+
+    // Scans the data region and the index. Chunk payloads are decompressed
+    // and re-hashed, which reads the whole lake.
+    pub fn fsck(&self) -> UssResult<FsckReport> {
+        let mut report = FsckReport {
+            payloads_checked: !self.encrypted || self.key.is_some(),
+            ..Default::default()
+        };
+
+        self.check_header(&mut report);
+
+        let data_end = std::cmp::min(self.data_next(), self.mapped_units());
+        let mut starts: HashSet<u32> = HashSet::new();
+        let mut seen: HashSet<[u8; 50]> = HashSet::new();
+        let mut offset = self.header().data_offset;
+
+        while offset < data_end {
+            let header = read_header(&self.data, offset);
+
+            if header.hash[0] == 0 {
+                offset += 1;
+                continue;
+            }
+
+            let units = header.units();
+
+            if offset + units <= data_end && self.is_superseded(&header.hash, offset) {
+                report.superseded.push(FsckChunk {
+                    offset,
+                    hash: hash_string(&header.hash),
+                });
+
+                offset += units;
+                continue;
+            }
+
+            report.chunks += 1;
+            starts.insert(offset);
+
+            if offset + units > data_end {
+                report.corrupt.push(FsckCorruptChunk {
+                    offset,
+                    hash: hash_string(&header.hash),
+                    reason: "chunk extends past data_next".to_owned(),
+                });
+
+                break;
+            }
+
+            if !crate::hasher::verify_hash_integrity(&header.hash) {
+                report.corrupt.push(FsckCorruptChunk {
+                    offset,
+                    hash: hash_string(&header.hash),
+                    reason: "invalid hash in chunk header".to_owned(),
+                });
+            } else if report.payloads_checked {
+                if let Err(err) = self.chunk_at(offset)?.verify() {
+                    report.corrupt.push(FsckCorruptChunk {
+                        offset,
+                        hash: hash_string(&header.hash),
+                        reason: match err {
+                            UssError::Corruption(_) => "data does not match hash".to_owned(),
+                            err => format!("{:?}", err),
+                        },
+                    });
+                }
+            }
+
+            if !seen.insert(header.hash) {
+                report.duplicates.push(FsckChunk {
+                    offset,
+                    hash: hash_string(&header.hash),
+                });
+            } else {
+                match self.probe(&header.hash) {
+                    Some((_, found)) if found == offset => (),
+                    _ => report.orphaned.push(FsckChunk {
+                        offset,
+                        hash: hash_string(&header.hash),
+                    }),
+                }
+            }
+
+            offset += units;
+        }
+
+        let mut pointed: HashSet<u32> = HashSet::new();
+
+        for (slot, chunk_offset) in self.index_entries() {
+            report.index_slots += 1;
+
+            if chunk_offset >= data_end {
+                report.unpublished_slots.push(FsckSlot {
+                    slot,
+                    offset: chunk_offset,
+                });
+            } else if !starts.contains(&chunk_offset) {
+                report.dangling_slots.push(FsckSlot {
+                    slot,
+                    offset: chunk_offset,
+                });
+            } else if !pointed.insert(chunk_offset) {
+                report.duplicate_slots.push(FsckSlot {
+                    slot,
+                    offset: chunk_offset,
+                });
+            }
+        }
+
+        let index_used = self.header().index_used.load(Ordering::Acquire) as usize;
+
+        // only the bucketed index keeps count
+        if self.index_format() == IndexFormat::Bucketed && index_used != report.index_slots {
+            report.header_errors.push(format!(
+                "index_used {} does not match {} used slots",
+                index_used, report.index_slots
+            ));
+        }
+
+        Ok(report)
+    }
+}
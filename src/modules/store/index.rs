@@ -0,0 +1,440 @@
+use super::*;
+use serde::Serialize;
+
+// Linear: u32 chunk offsets, linear probing from checksum % index_mod.
+// Bucketed: 64-byte buckets of 8 (offset, fingerprint) entries, probing
+// bucket by bucket from checksum % index_mod, so a lookup only reads chunk
+// headers whose fingerprint matches.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub enum IndexFormat {
+    Linear,
+    #[default]
+    Bucketed,
+}
+
+impl IndexFormat {
+    // value of DataLakeHeader::index_format; lakes from before it are zeroed
+    pub(super) fn from_header(value: u32) -> Option<IndexFormat> {
+        match value {
+            0 => Some(IndexFormat::Linear),
+            1 => Some(IndexFormat::Bucketed),
+            _ => None,
+        }
+    }
+
+    pub(super) fn to_header(self) -> u32 {
+        match self {
+            IndexFormat::Linear => 0,
+            IndexFormat::Bucketed => 1,
+        }
+    }
+}
+
+pub(super) const BUCKET_ENTRIES: u32 = 8;
+// each entry is an offset followed by a fingerprint
+pub(super) const BUCKET_U32S: u32 = BUCKET_ENTRIES * 2;
+
+// inserts fail beyond this, so probe sequences stay short
+const MAX_LOAD_PERCENT: u64 = 90;
+
+fn fingerprint(hash: &[u8; 50]) -> u32 {
+    crate::hasher::checksum_u32(&hash[25..], 25)
+}
+
+#[derive(Debug, Default)]
+pub(super) struct Probe {
+    // (slot, chunk offset) of the matching entry
+    pub found: Option<(u32, u32)>,
+    // index entries looked at
+    pub entries: u32,
+    // chunk headers read to compare hashes
+    pub headers: u32,
+}
+
+impl DataLake {
+    pub fn index_format(&self) -> IndexFormat {
+        IndexFormat::from_header(self.header().index_format).unwrap_or(IndexFormat::Linear)
+    }
+
+    // index entries the lake can hold
+    pub fn index_capacity(&self) -> u32 {
+        match self.index_format() {
+            IndexFormat::Linear => self.header().index_max - self.header().index_offset_u32,
+            IndexFormat::Bucketed => self.header().index_mod * BUCKET_ENTRIES,
+        }
+    }
+
+    fn matches(&self, hash: &[u8; 50], chunk_offset: u32, probe: &mut Probe) -> bool {
+        probe.headers += 1;
+
+        self.in_data_region(chunk_offset) && &read_header(&self.data, chunk_offset).hash == hash
+    }
+
+    pub(super) fn probe_with_stats(&self, hash: &[u8; 50]) -> Probe {
+        match self.index_format() {
+            IndexFormat::Linear => self.probe_linear(hash),
+            IndexFormat::Bucketed => self.probe_bucketed(hash),
+        }
+    }
+
+    // walks the index from the hash's home slot, returns (slot, chunk offset)
+    pub(super) fn probe(&self, hash: &[u8; 50]) -> Option<(u32, u32)> {
+        self.probe_with_stats(hash).found
+    }
+
+    fn probe_linear(&self, hash: &[u8; 50]) -> Probe {
+        let mut probe = Probe::default();
+        let mut index_offset = self.get_index_offset(hash);
+
+        while index_offset < self.header().index_max {
+            let chunk_offset = self.data.read_u32(index_offset);
+
+            probe.entries += 1;
+
+            if chunk_offset == 0 {
+                break;
+            }
+
+            if self.matches(hash, chunk_offset, &mut probe) {
+                probe.found = Some((index_offset, chunk_offset));
+                break;
+            }
+
+            index_offset += 1;
+        }
+
+        probe
+    }
+
+    fn bucket_start(&self, bucket: u32) -> u32 {
+        self.header().index_offset_u32 + bucket * BUCKET_U32S
+    }
+
+    fn probe_bucketed(&self, hash: &[u8; 50]) -> Probe {
+        let mut probe = Probe::default();
+        let buckets = self.header().index_mod;
+        let home = crate::hasher::checksum_u32(hash, 50) % buckets;
+        let fingerprint = fingerprint(hash);
+
+        // inserts never skip a free entry, so the first bucket with one ends
+        // the sequence; the load limit guarantees there is such a bucket
+        for step in 0..buckets {
+            let start = self.bucket_start((home + step) % buckets);
+
+            for slot in (start..start + BUCKET_U32S).step_by(2) {
+                let chunk_offset = self.data.read_u32(slot);
+
+                probe.entries += 1;
+
+                if chunk_offset == 0 {
+                    return probe;
+                }
+
+                if self.data.read_u32(slot + 1) == fingerprint
+                    && self.matches(hash, chunk_offset, &mut probe)
+                {
+                    probe.found = Some((slot, chunk_offset));
+                    return probe;
+                }
+            }
+        }
+
+        probe
+    }
+
+    // whether probing for the hash in the header of the chunk an entry
+    // points at finds that entry, as it does unless the header is damaged
+    fn leads_back(&self, slot: u32, chunk_offset: u32) -> bool {
+        if !self.in_data_region(chunk_offset) {
+            return false;
+        }
+
+        let stored = read_header(&self.data, chunk_offset).hash;
+
+        matches!(self.probe(&stored), Some((found, _)) if found == slot)
+    }
+
+    // The one slot on hash's probe path whose chunk header can't be what was
+    // indexed there, as when the hash in the header got corrupted, so probe()
+    // no longer finds it. None if there are several: a damaged header doesn't
+    // tell which hash it was stored under.
+    pub(super) fn probe_damaged(&self, hash: &[u8; 50]) -> Option<(u32, u32)> {
+        let mut damaged = Vec::new();
+
+        match self.index_format() {
+            IndexFormat::Linear => {
+                let mut slot = self.get_index_offset(hash);
+
+                while slot < self.header().index_max {
+                    let chunk_offset = self.data.read_u32(slot);
+
+                    if chunk_offset == 0 {
+                        break;
+                    }
+
+                    if !self.leads_back(slot, chunk_offset) {
+                        damaged.push((slot, chunk_offset));
+                    }
+
+                    slot += 1;
+                }
+            }
+            IndexFormat::Bucketed => {
+                let buckets = self.header().index_mod;
+                let home = crate::hasher::checksum_u32(hash, 50) % buckets;
+                let fingerprint = fingerprint(hash);
+
+                'buckets: for step in 0..buckets {
+                    let start = self.bucket_start((home + step) % buckets);
+
+                    for slot in (start..start + BUCKET_U32S).step_by(2) {
+                        let chunk_offset = self.data.read_u32(slot);
+
+                        if chunk_offset == 0 {
+                            break 'buckets;
+                        }
+
+                        // the fingerprint was taken from the hash at insert
+                        if self.data.read_u32(slot + 1) == fingerprint
+                            && !self.leads_back(slot, chunk_offset)
+                        {
+                            damaged.push((slot, chunk_offset));
+                        }
+                    }
+                }
+            }
+        }
+
+        match damaged[..] {
+            [found] => Some(found),
+            _ => None,
+        }
+    }
+
+    // index_max is the first u32 of the data region, so it must not be
+    // written; returns the slot used
+    pub(super) fn index_insert(
+        &self,
+        map: &mut memmap::MmapMut,
+        hash: &[u8; 50],
+        offset: u32,
+    ) -> UssResult<u32> {
+        match self.index_format() {
+            IndexFormat::Linear => self.insert_linear(map, hash, offset),
+            IndexFormat::Bucketed => self.insert_bucketed(map, hash, offset),
+        }
+    }
+
+    pub(super) fn store_u32(map: &mut memmap::MmapMut, index_offset: u32, value: u32) {
+        let map_offset = (index_offset as usize) << 2;
+        let slot = map[map_offset..map_offset + 4].as_mut_ptr() as *const AtomicU32;
+
+        unsafe { (*slot).store(value.to_le(), Ordering::Release) };
+    }
+
+    fn insert_linear(
+        &self,
+        map: &mut memmap::MmapMut,
+        hash: &[u8; 50],
+        offset: u32,
+    ) -> UssResult<u32> {
+        let mut index_offset = self.get_index_offset(hash);
+
+        loop {
+            if index_offset >= self.header().index_max {
+                return Err(UssError::StaticError("DataLake index ran out of space."));
+            }
+
+            if self.data.read_u32(index_offset) != 0 {
+                index_offset += 1;
+                continue;
+            }
+
+            DataLake::store_u32(map, index_offset, offset);
+
+            return Ok(index_offset);
+        }
+    }
+
+    fn insert_bucketed(
+        &self,
+        map: &mut memmap::MmapMut,
+        hash: &[u8; 50],
+        offset: u32,
+    ) -> UssResult<u32> {
+        let used = self.header().index_used.load(Ordering::Acquire);
+
+        if (used as u64 + 1) * 100 > self.index_capacity() as u64 * MAX_LOAD_PERCENT {
+            return Err(UssError::StaticError("DataLake index is full."));
+        }
+
+        let buckets = self.header().index_mod;
+        let home = crate::hasher::checksum_u32(hash, 50) % buckets;
+
+        for step in 0..buckets {
+            let start = self.bucket_start((home + step) % buckets);
+
+            for slot in (start..start + BUCKET_U32S).step_by(2) {
+                if self.data.read_u32(slot) != 0 {
+                    continue;
+                }
+
+                // readers only look at the fingerprint of a non-zero offset
+                DataLake::store_u32(map, slot + 1, fingerprint(hash));
+                DataLake::store_u32(map, slot, offset);
+
+                self.header().index_used.store(used + 1, Ordering::Release);
+
+                return Ok(slot);
+            }
+        }
+
+        Err(UssError::StaticError("DataLake index ran out of space."))
+    }
+
+    // Empties a slot returned by index_insert(). Linear probe chains may run
+    // through it, so only the latest inserts can be undone, newest first.
+    pub(super) fn index_remove(&self, map: &mut memmap::MmapMut, slot: u32) {
+        DataLake::store_u32(map, slot, 0);
+
+        if self.index_format() == IndexFormat::Bucketed {
+            DataLake::store_u32(map, slot + 1, 0);
+            self.header().index_used.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+
+    pub(super) fn flush_index(&self, map: &memmap::MmapMut) -> UssResult<()> {
+        let start = (self.header().index_offset_u32 as usize) << 2;
+        let end = offset_to_data_offset(self.header().data_offset);
+
+        map.flush_range(start, end - start).map_err(to_error)
+    }
+
+    // (slot, chunk offset) of every used index entry, in table order
+    pub(super) fn index_entries(&self) -> Vec<(u32, u32)> {
+        let header = self.header();
+        let start = header.index_offset_u32;
+        let end = std::cmp::min(header.index_max, header.data_offset << 6);
+
+        let step = match self.index_format() {
+            IndexFormat::Linear => 1,
+            IndexFormat::Bucketed => 2,
+        };
+
+        (start..end)
+            .step_by(step)
+            .map(|slot| (slot, self.data.read_u32(slot)))
+            .filter(|(_, chunk_offset)| *chunk_offset != 0)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // fills a lake of the format to 80% of its index and reopens it
+    fn filled(format: IndexFormat) -> (DataLake, Vec<[u8; 50]>) {
+        filled_with(format, |capacity| capacity * 4 / 5)
+    }
+
+    // stores count(index capacity) chunks and reopens the lake
+    fn filled_with(
+        format: IndexFormat,
+        count: impl Fn(usize) -> usize,
+    ) -> (DataLake, Vec<[u8; 50]>) {
+        let path = test_path(&format!("index-{:?}.lake", format));
+        let options = DataLakeOptions {
+            index_format: format,
+            ..Default::default()
+        };
+
+        let lake = DataLake::create_with_options(&path, 1 << 20, options).unwrap();
+        let count = count(lake.index_capacity() as usize);
+        let hashes = (0..count)
+            .map(|i| {
+                lake.put(format!("stored {}", i).as_bytes())
+                    .unwrap()
+                    .header
+                    .hash
+            })
+            .collect();
+
+        drop(lake);
+
+        (DataLake::load(&path, true).unwrap(), hashes)
+    }
+
+    #[test]
+    fn round_trip() {
+        for format in [IndexFormat::Linear, IndexFormat::Bucketed] {
+            let (lake, hashes) = filled(format);
+
+            assert_eq!(lake.index_format(), format);
+            assert_eq!(lake.len(), hashes.len());
+
+            for (i, hash) in hashes.iter().enumerate() {
+                let data = lake.get(hash).unwrap().read().unwrap();
+
+                assert_eq!(data, format!("stored {}", i).as_bytes());
+            }
+
+            for i in 0..100 {
+                let absent = crate::hasher::hash(format!("absent {}", i).as_bytes());
+
+                assert!(lake.get(&absent).is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn fingerprints_skip_headers() {
+        let (lake, hashes) = filled(IndexFormat::Bucketed);
+        let headers: usize = hashes
+            .iter()
+            .map(|hash| lake.probe_with_stats(hash).headers as usize)
+            .sum();
+
+        // a hit reads its own header, and rarely one with a colliding fingerprint
+        assert!(headers < hashes.len() * 11 / 10);
+
+        for i in 0..100 {
+            let absent = crate::hasher::hash(format!("absent {}", i).as_bytes());
+
+            assert!(lake.probe_with_stats(&absent).headers <= 1);
+        }
+    }
+
+    #[test]
+    fn bucketed_reads_fewer_headers_than_linear() {
+        // same number of chunks in both, 80% of the smaller index
+        let count = |_: usize| 800;
+        let absent: Vec<[u8; 50]> = (0..1000)
+            .map(|i| crate::hasher::hash(format!("absent {}", i).as_bytes()))
+            .collect();
+
+        let [linear, bucketed] = [IndexFormat::Linear, IndexFormat::Bucketed].map(|format| {
+            let (lake, hashes) = filled_with(format, count);
+            let total = |hashes: &[[u8; 50]]| {
+                hashes.iter().fold((0, 0), |(entries, headers), hash| {
+                    let probe = lake.probe_with_stats(hash);
+
+                    (entries + probe.entries, headers + probe.headers)
+                })
+            };
+
+            assert!(lake.index_capacity() >= 1000);
+
+            (total(&hashes), total(&absent))
+        });
+
+        let ((_, linear_hits), (linear_entries, linear_misses)) = linear;
+        let ((_, bucketed_hits), (bucketed_entries, bucketed_misses)) = bucketed;
+
+        // linear probing reads the header behind every occupied slot it passes
+        assert!(bucketed_hits < linear_hits);
+        assert!(bucketed_misses * 10 < linear_misses);
+        // and a miss runs to the next empty slot, a bucket at a time for bucketed
+        assert!(bucketed_entries < linear_entries * 2);
+    }
+}
@@ -0,0 +1,144 @@
+use super::*;
+
+// Chunks in storage order, from a scan of the data region up to data_next
+// as it was when iter() was called.
+pub struct Chunks<'a> {
+    lake: &'a DataLake,
+    offset: u32,
+    end: u32,
+    min_size: usize,
+    max_size: usize,
+    min_ratio: f64,
+    max_ratio: f64,
+}
+
+impl<'a> Chunks<'a> {
+    // uncompressed size in bytes, inclusive
+    pub fn min_size(mut self, size: usize) -> Self {
+        self.min_size = size;
+        self
+    }
+
+    pub fn max_size(mut self, size: usize) -> Self {
+        self.max_size = size;
+        self
+    }
+
+    // uncompressed / stored length, inclusive; raw chunks have a ratio of 1
+    pub fn min_ratio(mut self, ratio: f64) -> Self {
+        self.min_ratio = ratio;
+        self
+    }
+
+    pub fn max_ratio(mut self, ratio: f64) -> Self {
+        self.max_ratio = ratio;
+        self
+    }
+
+    fn accepts(&self, header: &DataChunkHeader) -> bool {
+        let size = header.uncompressed_length as usize;
+
+        if size < self.min_size || size > self.max_size {
+            return false;
+        }
+
+        let ratio = size as f64 / std::cmp::max(header.compressed_length, 1) as f64;
+
+        ratio >= self.min_ratio && ratio <= self.max_ratio
+    }
+}
+
+impl<'a> Iterator for Chunks<'a> {
+    type Item = DataChunk;
+
+    fn next(&mut self) -> Option<DataChunk> {
+        while self.offset < self.end {
+            let offset = self.offset;
+            let header = read_header(&self.lake.data, offset);
+
+            if header.hash[0] == 0 {
+                // unit freed by recompress()
+                self.offset += 1;
+                continue;
+            }
+
+            self.offset += header.units();
+
+            if self.accepts(&header) && !self.lake.is_superseded(&header.hash, offset) {
+                return self.lake.chunk_at(offset).ok();
+            }
+        }
+
+        None
+    }
+}
+
+impl DataLake {
+    pub fn iter(&self) -> Chunks<'_> {
+        self.iter_from(self.header().data_offset)
+    }
+
+    // chunks from a unit offset on, e.g. a previous data_next
+    pub(super) fn iter_from(&self, offset: u32) -> Chunks<'_> {
+        Chunks {
+            lake: self,
+            offset: std::cmp::max(offset, self.header().data_offset),
+            end: std::cmp::min(self.data_next(), self.mapped_units()),
+            min_size: 0,
+            max_size: usize::MAX,
+            min_ratio: 0.0,
+            max_ratio: f64::INFINITY,
+        }
+    }
+
+    // number of chunks; scans the data region
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+
+    pub fn hashes(&self) -> impl Iterator<Item = [u8; 50]> + '_ {
+        self.iter().map(|chunk| chunk.header.hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::tests::noise;
+
+    #[test]
+    fn filters_by_size_and_ratio() {
+        let path = test_path("iter-filters.lake");
+        let lake = DataLake::create(&path, 1 << 20).unwrap();
+        let text = |length: usize| b"compressible ".repeat(length / 13);
+        let compressible: Vec<[u8; 50]> = [260, 1300, 3900]
+            .map(|length| lake.put(&text(length)).unwrap().header.hash)
+            .to_vec();
+        let incompressible: Vec<[u8; 50]> = [200, 1000, 3000]
+            .map(|length| lake.put(&noise(length, 0)).unwrap().header.hash)
+            .to_vec();
+
+        let hashes =
+            |chunks: Chunks| -> Vec<[u8; 50]> { chunks.map(|chunk| chunk.header.hash).collect() };
+
+        assert_eq!(lake.len(), 6);
+
+        // raw chunks have a ratio of exactly 1
+        assert_eq!(hashes(lake.iter().max_ratio(1.0)), incompressible);
+        assert_eq!(hashes(lake.iter().min_ratio(2.0)), compressible);
+
+        assert_eq!(
+            hashes(lake.iter().min_size(1000).max_size(1300)),
+            [compressible[1], incompressible[1]]
+        );
+        assert_eq!(
+            hashes(lake.iter().min_size(1000).min_ratio(2.0)),
+            compressible[1..]
+        );
+        assert!(hashes(lake.iter().min_size(4000)).is_empty());
+    }
+}
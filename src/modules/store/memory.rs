@@ -0,0 +1,49 @@
+use super::chunk_store::ChunkStore;
+use crate::modules::error::{UssError, UssResult};
+use std::{collections::HashMap, sync::RwLock};
+
+// Keeps chunks in a HashMap, for tests and short-lived trees.
+#[derive(Default)]
+pub struct MemoryStore {
+    chunks: RwLock<HashMap<[u8; 50], Vec<u8>>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.chunks.read().map_or(0, |chunks| chunks.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl ChunkStore for MemoryStore {
+    fn get(&self, hash: &[u8; 50]) -> UssResult<Option<Vec<u8>>> {
+        match self.chunks.read() {
+            Ok(chunks) => Ok(chunks.get(hash).cloned()),
+            Err(_) => Err(UssError::MutexPoison),
+        }
+    }
+
+    fn put(&self, data: &[u8]) -> UssResult<[u8; 50]> {
+        let hash = crate::hasher::hash(data);
+
+        match self.chunks.write() {
+            Ok(mut chunks) => chunks.entry(hash).or_insert_with(|| data.to_vec()),
+            Err(_) => return Err(UssError::MutexPoison),
+        };
+
+        Ok(hash)
+    }
+
+    fn has(&self, hash: &[u8; 50]) -> bool {
+        self.chunks
+            .read()
+            .is_ok_and(|chunks| chunks.contains_key(hash))
+    }
+}
@@ -0,0 +1,342 @@
+use super::*;
+use serde::Serialize;
+
+#[derive(Debug, Default, Serialize)]
+pub struct MergeReport {
+    pub chunks: usize,
+    pub merged: usize,
+    // hashes the destination already held
+    pub existing: usize,
+    pub payload_bytes: u64,
+    // source chunks that didn't match their hash, left out
+    pub corrupt: Vec<String>,
+    // refs of the sources this lake didn't have
+    pub refs: usize,
+    // refs whose name this lake or an earlier source already points elsewhere
+    pub ref_conflicts: Vec<RefConflict>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RefConflict {
+    pub name: String,
+    // where the ref points after the merge
+    pub kept: String,
+    pub other: String,
+}
+
+impl DataLake {
+    // Stores a chunk from a portable payload: deflate output, or the data
+    // itself when its length equals uncompressed_length. The payload is
+    // decoded to check the hash, then stored as is, unless this lake uses a
+    // dictionary or a key, or is too old to store raw payloads, and has to
+    // encode the data itself.
+    pub fn put_payload(
+        &self,
+        hash: &[u8; 50],
+        uncompressed_length: u16,
+        payload: &[u8],
+    ) -> UssResult<DataChunk> {
+        if let Some(chunk) = self.get(hash) {
+            return Ok(chunk);
+        }
+
+        if self.readonly {
+            return Err(UssError::StaticError(
+                "Must not call put_payload() on readonly lake.",
+            ));
+        }
+
+        let (header, payload) = self.encode_portable(hash, uncompressed_length, payload)?;
+        let _writer = self.lock_appends()?;
+
+        if let Some(chunk) = self.get(hash) {
+            return Ok(chunk);
+        }
+
+        self.append_chunk(&mut *self.lock_map()?, header, &payload)
+    }
+
+    // the header and payload put_payload() stores
+    pub(super) fn encode_portable(
+        &self,
+        hash: &[u8; 50],
+        uncompressed_length: u16,
+        payload: &[u8],
+    ) -> UssResult<(DataChunkHeader, Vec<u8>)> {
+        let length = uncompressed_length as usize;
+
+        let data = if payload.len() == length {
+            payload.to_vec()
+        } else {
+            self.with_decompressor(|decompressors| decompressors.decompress(payload, length))?
+        };
+
+        if !crate::hasher::verify(hash, &data) {
+            return Err(UssError::Corruption(*hash));
+        }
+
+        if self.dictionary.is_some()
+            || self.key.is_some()
+            || self.encrypted
+            || (payload.len() == length && !self.raw_payloads())
+        {
+            return self.encode_chunk(*hash, &data);
+        }
+
+        let header = DataChunkHeader {
+            hash: *hash,
+            uncompressed_length,
+            compressed_length: payload.len() as u16,
+        };
+
+        Ok((header, payload.to_vec()))
+    }
+
+    // Copies every chunk of the sources that this lake doesn't hold yet,
+    // without recompressing where the payloads are portable, then adds their
+    // named refs. A name that is already taken keeps pointing where it did,
+    // and is reported as a conflict.
+    pub fn merge(&self, sources: &[&DataLake]) -> UssResult<MergeReport> {
+        let mut report = MergeReport::default();
+
+        if sources
+            .iter()
+            .any(|source| source.encrypted && source.key.is_none())
+        {
+            // its chunks would all fail to decode, and look corrupt
+            return Err(UssError::StaticError(
+                "DataLake::merge: encrypted source was loaded without its key",
+            ));
+        }
+
+        for source in sources.iter() {
+            let refs_offset = source.refs_offset();
+
+            for chunk in source.iter() {
+                // the source's ref table, whose refs are merged below
+                if chunk.offset == refs_offset {
+                    continue;
+                }
+
+                report.chunks += 1;
+
+                if self.get(&chunk.header.hash).is_some() {
+                    report.existing += 1;
+                    continue;
+                }
+
+                let stored = source.archive_payload(&chunk).and_then(|payload| {
+                    let stored = self.put_payload(
+                        &chunk.header.hash,
+                        chunk.header.uncompressed_length,
+                        &payload,
+                    )?;
+
+                    Ok(stored.header.compressed_length)
+                });
+
+                match stored {
+                    Ok(length) => {
+                        report.merged += 1;
+                        report.payload_bytes += length as u64;
+                    }
+                    Err(UssError::Corruption(hash)) => {
+                        report.corrupt.push(fsck::hash_string(&hash));
+                    }
+                    // a source chunk that fails to decode is as good as corrupt
+                    Err(_) if chunk.verify().is_err() => {
+                        report.corrupt.push(fsck::hash_string(&chunk.header.hash));
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+
+        let mut incoming = Vec::new();
+
+        for source in sources.iter() {
+            incoming.extend(source.refs()?);
+        }
+
+        if incoming.is_empty() {
+            return Ok(report);
+        }
+
+        self.update_refs(|refs| {
+            for (name, hash) in incoming {
+                match refs.get(&name) {
+                    None => {
+                        refs.insert(name, hash);
+                        report.refs += 1;
+                    }
+                    Some(kept) if *kept == hash => (),
+                    Some(kept) => report.ref_conflicts.push(RefConflict {
+                        name,
+                        kept: fsck::hash_string(kept),
+                        other: fsck::hash_string(&hash),
+                    }),
+                }
+            }
+        })?;
+
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::tests::{equal_length_data, noise};
+
+    fn items() -> Vec<Vec<u8>> {
+        vec![
+            b"short".to_vec(),
+            "compressible ".repeat(100).into_bytes(),
+            // deflate output longer than the data, and exactly as long
+            noise(1000, 0),
+            equal_length_data(),
+        ]
+    }
+
+    fn check(lake: &DataLake, report: &MergeReport) {
+        assert!(report.corrupt.is_empty(), "{:?}", report);
+        assert_eq!(report.merged, items().len());
+
+        for item in items().iter() {
+            let hash = crate::hasher::hash(item);
+
+            assert_eq!(lake.get(&hash).unwrap().read().unwrap(), *item);
+        }
+
+        assert!(lake.fsck().unwrap().is_clean());
+    }
+
+    #[test]
+    fn merges_legacy_lakes() {
+        let mut source = DataLake::create(&test_path("merge-legacy.lake"), 1 << 20).unwrap();
+
+        source.header_mut().format = 0;
+
+        for item in items().iter() {
+            source.put(item).unwrap();
+        }
+
+        let target = DataLake::create(&test_path("merge-legacy-target.lake"), 1 << 20).unwrap();
+
+        check(&target, &target.merge(&[&source]).unwrap());
+    }
+
+    #[test]
+    fn merges_into_legacy_lakes() {
+        let source = DataLake::create(&test_path("merge-raw.lake"), 1 << 20).unwrap();
+
+        for item in items().iter() {
+            source.put(item).unwrap();
+        }
+
+        let mut target = DataLake::create(&test_path("merge-raw-target.lake"), 1 << 20).unwrap();
+
+        target.header_mut().format = 0;
+
+        let report = target.merge(&[&source]).unwrap();
+
+        check(&target, &report);
+        assert_eq!(target.merge(&[&source]).unwrap().existing, items().len());
+    }
+
+    #[test]
+    fn unions_refs_and_reports_conflicts() {
+        let lakes: Vec<DataLake> = ["merge-refs-a.lake", "merge-refs-b.lake", "merge-refs.lake"]
+            .iter()
+            .map(|name| DataLake::create(&test_path(name), 1 << 20).unwrap())
+            .collect();
+        let hashes: Vec<[u8; 50]> = (0..4)
+            .map(|i| {
+                lakes[i % 2]
+                    .put(format!("root {}", i).as_bytes())
+                    .unwrap()
+                    .header
+                    .hash
+            })
+            .collect();
+
+        // one table each, earlier versions would be merged as plain chunks
+        let tables = [
+            vec![("shared", hashes[0]), ("a", hashes[2])],
+            vec![("shared", hashes[0]), ("b", hashes[1]), ("a", hashes[3])],
+            vec![("b", hashes[3])],
+        ];
+
+        for (lake, table) in lakes.iter().zip(tables) {
+            lake.update_refs(|refs| {
+                refs.extend(table.iter().map(|(name, hash)| (name.to_string(), *hash)))
+            })
+            .unwrap();
+        }
+
+        let report = lakes[2].merge(&[&lakes[0], &lakes[1]]).unwrap();
+        let refs = lakes[2].refs().unwrap();
+
+        // the ref tables themselves aren't copied as chunks
+        assert_eq!((report.chunks, report.merged), (4, 4));
+        assert_eq!(report.refs, 2);
+        assert_eq!(refs.len(), 3);
+        assert_eq!(refs["shared"], hashes[0]);
+        assert_eq!(refs["a"], hashes[2]);
+        assert_eq!(refs["b"], hashes[3]);
+
+        let mut conflicts: Vec<(&str, &str, &str)> = report
+            .ref_conflicts
+            .iter()
+            .map(|conflict| {
+                (
+                    conflict.name.as_str(),
+                    conflict.kept.as_str(),
+                    conflict.other.as_str(),
+                )
+            })
+            .collect();
+
+        conflicts.sort();
+
+        assert_eq!(
+            conflicts,
+            [
+                (
+                    "a",
+                    &fsck::hash_string(&hashes[2])[..],
+                    &fsck::hash_string(&hashes[3])[..]
+                ),
+                (
+                    "b",
+                    &fsck::hash_string(&hashes[3])[..],
+                    &fsck::hash_string(&hashes[1])[..]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn encrypted_source_without_its_key_is_an_error() {
+        let path = test_path("merge-encrypted.lake");
+        let options = DataLakeOptions {
+            key: Some([3; 32]),
+            ..Default::default()
+        };
+
+        {
+            let source = DataLake::create_with_options(&path, 1 << 20, options).unwrap();
+
+            source.put(b"sealed").unwrap();
+        }
+
+        let source = DataLake::load(&path, true).unwrap();
+        let target = DataLake::create(&test_path("merge-encrypted-target.lake"), 1 << 20).unwrap();
+
+        assert!(matches!(
+            target.merge(&[&source]),
+            Err(UssError::StaticError(_))
+        ));
+        assert!(target.is_empty());
+    }
+}
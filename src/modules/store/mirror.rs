@@ -0,0 +1,408 @@
+use super::*;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::sync::atomic::AtomicU64;
+
+#[derive(Debug, Default, Serialize)]
+pub struct MirrorScrubReport {
+    pub checked: usize,
+    // copies stored again from the other side
+    pub healed: usize,
+    // bad copies that couldn't be replaced, e.g. on a readonly side
+    pub unhealed: usize,
+    // hashes with no good copy on either side
+    pub lost: usize,
+}
+
+// what one side of the mirror holds for a hash
+enum Replica {
+    Good(Vec<u8>),
+    Missing,
+    // fails to decode, or doesn't match its hash
+    Bad,
+}
+
+struct Settled {
+    data: Option<Vec<u8>>,
+    healed: usize,
+    unhealed: usize,
+}
+
+impl DataLake {
+    // Stores a new copy of the chunk for data after its stored copy turned
+    // out corrupt, and repoints the index slot at it, also when the damage
+    // is to the hash in the old copy's header. The old copy is never written
+    // to, since readers may still be decoding it; scans skip it as
+    // superseded. A missing chunk is stored under hash, whichever algorithm
+    // made it.
+    pub fn heal(&self, hash: &[u8; 50], data: &[u8]) -> UssResult<DataChunk> {
+        if self.readonly {
+            return Err(UssError::StaticError(
+                "Must not call heal() on readonly lake.",
+            ));
+        }
+
+        if !crate::hasher::verify(hash, data) {
+            return Err(UssError::Corruption(*hash));
+        }
+
+        let hash = *hash;
+        let (header, payload) = self.encode_chunk(hash, data)?;
+        let _writer = self.lock_appends()?;
+        let mut map = self.lock_map()?;
+
+        let slot = match self.probe(&hash).or_else(|| self.probe_damaged(&hash)) {
+            Some((slot, _)) => slot,
+            None => return self.append_chunk(&mut map, header, &payload),
+        };
+
+        let next = self.data_next();
+        let units = self.write_chunk(&mut map, next, &header, &payload)?;
+
+        self.header()
+            .data_next
+            .store(next + units, Ordering::Release);
+
+        // the fingerprint next to a bucketed slot stays the same
+        DataLake::store_u32(&mut map, slot, next);
+        self.filter_insert(&hash, next, units);
+
+        // the cached handle and payload may hold the corrupt copy
+        self.chunks.remove(&hash);
+
+        Ok(self.written_chunk(header, next))
+    }
+
+    // a copy left behind by heal(), whose hash is indexed further on
+    pub(super) fn is_superseded(&self, hash: &[u8; 50], offset: u32) -> bool {
+        matches!(self.probe(hash), Some((_, found)) if found > offset)
+    }
+}
+
+// Two lake files holding the same chunks, e.g. on different disks. Reads
+// are served by the primary; a missing or bad copy is read from the other
+// side and stored again from it.
+pub struct MirroredLake {
+    sides: [DataLake; 2],
+    healed: AtomicU64,
+}
+
+impl MirroredLake {
+    pub fn new(primary: DataLake, secondary: DataLake) -> MirroredLake {
+        MirroredLake {
+            sides: [primary, secondary],
+            healed: AtomicU64::new(0),
+        }
+    }
+
+    pub fn create(
+        primary: &str,
+        secondary: &str,
+        file_size: u64,
+        options: DataLakeOptions,
+    ) -> UssResult<MirroredLake> {
+        Ok(MirroredLake::new(
+            DataLake::create_with_options(primary, file_size, options.clone())?,
+            DataLake::create_with_options(secondary, file_size, options)?,
+        ))
+    }
+
+    pub fn open(
+        primary: &str,
+        secondary: &str,
+        readonly: bool,
+        options: DataLakeOptions,
+    ) -> UssResult<MirroredLake> {
+        Ok(MirroredLake::new(
+            DataLake::load_with_options(primary, readonly, options.clone())?,
+            DataLake::load_with_options(secondary, readonly, options)?,
+        ))
+    }
+
+    pub fn primary(&self) -> &DataLake {
+        &self.sides[0]
+    }
+
+    pub fn secondary(&self) -> &DataLake {
+        &self.sides[1]
+    }
+
+    // copies healed since the mirror was opened
+    pub fn healed(&self) -> u64 {
+        self.healed.load(Ordering::Relaxed)
+    }
+
+    fn replica(lake: &DataLake, hash: &[u8; 50]) -> Replica {
+        let chunk = match lake.get(hash) {
+            Some(chunk) => chunk,
+            None => return Replica::Missing,
+        };
+
+        let mut buffer = Vec::new();
+
+        match lake.read(&chunk, &mut buffer) {
+            Ok(data) if crate::hasher::verify(hash, data) => Replica::Good(data.to_vec()),
+            _ => Replica::Bad,
+        }
+    }
+
+    // heals every side that doesn't hold a good copy
+    fn settle(&self, hash: &[u8; 50], replicas: [Replica; 2]) -> UssResult<Settled> {
+        let data = replicas.iter().find_map(|replica| match replica {
+            Replica::Good(data) => Some(data.clone()),
+            _ => None,
+        });
+
+        let data = match data {
+            Some(data) => data,
+            None if replicas.iter().all(|r| matches!(r, Replica::Missing)) => {
+                return Ok(Settled {
+                    data: None,
+                    healed: 0,
+                    unhealed: 0,
+                })
+            }
+            None => return Err(UssError::Corruption(*hash)),
+        };
+
+        let (mut healed, mut unhealed) = (0, 0);
+
+        for (lake, replica) in self.sides.iter().zip(replicas.iter()) {
+            if matches!(replica, Replica::Good(_)) {
+                continue;
+            }
+
+            match lake.heal(hash, &data) {
+                Ok(_) => healed += 1,
+                Err(_) => unhealed += 1,
+            }
+        }
+
+        self.healed.fetch_add(healed as u64, Ordering::Relaxed);
+
+        Ok(Settled {
+            data: Some(data),
+            healed,
+            unhealed,
+        })
+    }
+
+    // the secondary is only read when the primary's copy is missing or bad
+    pub fn get(&self, hash: &[u8; 50]) -> UssResult<Option<Vec<u8>>> {
+        let primary = match MirroredLake::replica(&self.sides[0], hash) {
+            Replica::Good(data) => return Ok(Some(data)),
+            replica => replica,
+        };
+
+        let secondary = MirroredLake::replica(&self.sides[1], hash);
+
+        Ok(self.settle(hash, [primary, secondary])?.data)
+    }
+
+    // returns the primary's chunk
+    pub fn put(&self, data: &[u8]) -> UssResult<DataChunk> {
+        let chunk = self.sides[0].put(data)?;
+
+        self.sides[1].put(data)?;
+
+        Ok(chunk)
+    }
+
+    // reads every hash on either side from both sides, healing bad copies
+    pub fn scrub(&self) -> UssResult<MirrorScrubReport> {
+        let mut report = MirrorScrubReport::default();
+        let mut hashes: HashSet<[u8; 50]> = HashSet::new();
+
+        for lake in self.sides.iter() {
+            hashes.extend(lake.hashes());
+        }
+
+        for hash in hashes.iter() {
+            let replicas = [
+                MirroredLake::replica(&self.sides[0], hash),
+                MirroredLake::replica(&self.sides[1], hash),
+            ];
+
+            report.checked += 1;
+
+            match self.settle(hash, replicas) {
+                Ok(settled) => {
+                    report.healed += settled.healed;
+                    report.unhealed += settled.unhealed;
+                }
+                Err(UssError::Corruption(_)) => report.lost += 1,
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(report)
+    }
+}
+
+impl ChunkStore for MirroredLake {
+    fn get(&self, hash: &[u8; 50]) -> UssResult<Option<Vec<u8>>> {
+        MirroredLake::get(self, hash)
+    }
+
+    fn put(&self, data: &[u8]) -> UssResult<[u8; 50]> {
+        Ok(MirroredLake::put(self, data)?.header.hash)
+    }
+
+    fn has(&self, hash: &[u8; 50]) -> bool {
+        self.sides.iter().any(|lake| lake.get(hash).is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::tests::noise;
+
+    // flips a byte of the stored payload of data
+    fn corrupt(path: &str, data: &[u8]) {
+        let mut file = std::fs::read(path).unwrap();
+        let at = file
+            .windows(32)
+            .position(|window| window == &data[..32])
+            .unwrap();
+
+        file[at + 10] ^= 0xff;
+        std::fs::write(path, file).unwrap();
+    }
+
+    // flips a character of the hash stored in the chunk header
+    fn corrupt_hash(path: &str, hash: &[u8; 50]) {
+        let mut file = std::fs::read(path).unwrap();
+        let at = file.windows(50).position(|window| window == hash).unwrap();
+
+        file[at + 5] = match file[at + 5] {
+            b'A' => b'B',
+            _ => b'A',
+        };
+        std::fs::write(path, file).unwrap();
+    }
+
+    #[test]
+    fn heal_appends_and_leaves_the_old_copy() {
+        let path = test_path("heal.lake");
+        let data = noise(1000, 0);
+        let hash = crate::hasher::hash(&data);
+
+        let old_offset = {
+            let lake = DataLake::create(&path, 1 << 20).unwrap();
+
+            lake.put(b"before").unwrap();
+
+            let offset = lake.put(&data).unwrap().offset;
+
+            lake.put(b"after").unwrap();
+            offset
+        };
+
+        corrupt(&path, &data);
+
+        let lake = DataLake::load(&path, false).unwrap();
+        let old_bytes = lake
+            .chunk_at(old_offset)
+            .unwrap()
+            .read_compressed()
+            .unwrap()
+            .to_vec();
+
+        assert!(lake.get(&hash).unwrap().verify().is_err());
+
+        let healed = lake.heal(&hash, &data).unwrap();
+
+        assert!(healed.offset > old_offset);
+        assert_eq!(lake.get(&hash).unwrap().read().unwrap(), data);
+        assert_eq!(
+            lake.chunk_at(old_offset)
+                .unwrap()
+                .read_compressed()
+                .unwrap(),
+            old_bytes
+        );
+        assert_eq!(lake.len(), 3);
+
+        let report = lake.fsck().unwrap();
+
+        assert!(report.is_clean(), "{:?}", report);
+        assert_eq!(report.superseded.len(), 1);
+        assert_eq!(report.superseded[0].offset, old_offset);
+    }
+
+    #[test]
+    fn mirror_heals_a_bad_primary() {
+        let (primary, secondary) = (test_path("mirror.a"), test_path("mirror.b"));
+        let data = noise(1000, 0);
+
+        let hash = {
+            let mirror =
+                MirroredLake::create(&primary, &secondary, 1 << 20, DataLakeOptions::default())
+                    .unwrap();
+
+            mirror.put(&data).unwrap().header.hash
+        };
+
+        corrupt(&primary, &data);
+
+        let mirror =
+            MirroredLake::open(&primary, &secondary, false, DataLakeOptions::default()).unwrap();
+
+        assert_eq!(mirror.get(&hash).unwrap().unwrap(), data);
+        assert_eq!(mirror.healed(), 1);
+        assert!(mirror.primary().get(&hash).unwrap().verify().is_ok());
+
+        let report = mirror.scrub().unwrap();
+
+        assert_eq!((report.checked, report.healed, report.lost), (1, 0, 0));
+    }
+
+    #[test]
+    fn heal_replaces_the_slot_of_a_damaged_header() {
+        for format in [IndexFormat::Linear, IndexFormat::Bucketed] {
+            let path = test_path(&format!("heal-header-{:?}.lake", format));
+            let options = DataLakeOptions {
+                index_format: format,
+                ..Default::default()
+            };
+            let data = noise(1000, 0);
+            let hash = crate::hasher::hash(&data);
+
+            {
+                let lake = DataLake::create_with_options(&path, 1 << 20, options).unwrap();
+
+                for i in 0..100 {
+                    lake.put(format!("neighbour {}", i).as_bytes()).unwrap();
+                }
+
+                lake.put(&data).unwrap();
+            }
+
+            corrupt_hash(&path, &hash);
+
+            let lake = DataLake::load(&path, false).unwrap();
+            let entries = lake.index_entries().len();
+
+            assert!(lake.get(&hash).is_none());
+
+            lake.heal(&hash, &data).unwrap();
+
+            assert_eq!(lake.index_entries().len(), entries);
+            assert_eq!(lake.get(&hash).unwrap().read().unwrap(), data);
+
+            for i in 0..100 {
+                let neighbour = crate::hasher::hash(format!("neighbour {}", i).as_bytes());
+
+                assert!(lake.get(&neighbour).is_some());
+            }
+
+            // the old copy is left as it was, and reported as corrupt
+            let report = lake.fsck().unwrap();
+
+            assert_eq!(report.corrupt.len(), 1);
+            assert!(report.dangling_slots.is_empty());
+            assert!(report.duplicate_slots.is_empty());
+        }
+    }
+}
@@ -1,9 +1,51 @@
+pub mod archive;
+pub mod batch;
+pub mod cache;
+pub mod chunk_store;
+pub mod directory;
+pub mod encryption;
+pub mod filter;
+pub mod fsck;
+pub mod index;
+pub mod iter;
+pub mod memory;
+pub mod merge;
+pub mod mirror;
+pub mod parity;
+pub mod refs;
+pub mod repair;
+pub mod sharded;
 pub mod sieve;
+pub mod stats;
+pub mod tiered;
+pub mod transaction;
 
-use crate::compression::compressor::CompressorCollection;
+use crate::compression::{
+    compressor::CompressorCollection, dictionary, CompressionProfile, DecompressorCollection,
+    Dictionary,
+};
 
-use super::{compression::decompress, error::*, mapping::*};
-use std::{collections::HashMap, io::Write, rc::Rc};
+pub use chunk_store::ChunkStore;
+pub use directory::DirectoryStore;
+pub use memory::MemoryStore;
+pub use mirror::MirroredLake;
+pub use sharded::ShardedLake;
+pub use tiered::TieredStore;
+
+use super::{error::*, mapping::*};
+use crate::hasher::{hash_with, HashAlgorithm};
+use cache::{CacheOptions, CacheStats, ChunkCache};
+use encryption::LakeKey;
+use filter::HashFilter;
+use index::IndexFormat;
+use std::{
+    io::Write,
+    ptr::NonNull,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+};
 
 #[derive(Copy, Clone)]
 #[repr(C)]
@@ -15,31 +57,79 @@ pub struct DataChunkHeader {
 
 const HEADER_SIZE: usize = std::mem::size_of::<DataChunkHeader>();
 
+impl DataChunkHeader {
+    // size of header + payload in 256-byte chunks
+    pub fn units(&self) -> u32 {
+        ((HEADER_SIZE + self.compressed_length as usize - 1) >> 8) as u32 + 1
+    }
+}
+
 #[derive(Clone)]
 pub struct DataChunk {
     pub header: DataChunkHeader,
-    mapping: Rc<MemoryMapping>,
+    mapping: Arc<MemoryMapping>,
     offset: u32,
+    dictionary: Option<Arc<Dictionary>>,
+    key: Option<Arc<LakeKey>>,
+    // re-hash data on every read
+    verify: bool,
+    // the lake stores payloads that don't shrink raw, see LAKE_FORMAT_RAW
+    raw_payloads: bool,
+    // payloads are encrypted; without the key they can't be read at all
+    encrypted: bool,
 }
 
 pub fn offset_to_data_offset(offset: u32) -> usize {
     (offset as usize) << 8
 }
 
-impl DataChunk {
-    pub fn at(mapping: Rc<MemoryMapping>, offset: u32) -> UssResult<Self> {
-        let offset_real = offset_to_data_offset(offset);
+// Lakes of this format store payloads that don't shrink raw, marked by
+// equal lengths. Older lakes always store the compressor's output, which
+// may be as long as the data or longer, so the rule doesn't apply to them.
+pub const LAKE_FORMAT_RAW: u32 = 1;
+
+// format of lakes made by create()
+pub const LAKE_FORMAT: u32 = LAKE_FORMAT_RAW;
 
-        let mapping_location = mapping.roref.as_ptr() as usize;
-        let offset_location = offset_real + mapping_location;
-        let pointer = offset_location as *const DataChunkHeader;
+fn encode_payload(compressed: Vec<u8>, data: &[u8], raw_payloads: bool) -> Vec<u8> {
+    if raw_payloads && compressed.len() >= data.len() {
+        data.to_vec()
+    } else {
+        compressed
+    }
+}
 
-        let header: DataChunkHeader = unsafe { std::ptr::read(pointer) };
+fn read_header(mapping: &MemoryMapping, offset: u32) -> DataChunkHeader {
+    let offset_real = offset_to_data_offset(offset);
+
+    let mapping_location = mapping.roref.as_ptr() as usize;
+    let offset_location = offset_real + mapping_location;
+    let pointer = offset_location as *const DataChunkHeader;
+
+    unsafe { std::ptr::read(pointer) }
+}
+
+// the lake header at the start of the mapping
+fn read_lake_header(mapping: &MemoryMapping) -> &DataLakeHeader {
+    unsafe { &*(mapping.roref.as_ptr() as *const DataLakeHeader) }
+}
+
+impl DataChunk {
+    pub fn at(mapping: Arc<MemoryMapping>, offset: u32) -> UssResult<Self> {
+        let header = read_header(&mapping, offset);
+        let lake = read_lake_header(&mapping);
+        let raw_payloads = lake.format >= LAKE_FORMAT_RAW;
+        let encrypted = lake.key_check != [0; 32];
 
         Ok(Self {
             header,
             mapping,
             offset,
+            dictionary: None,
+            key: None,
+            verify: false,
+            raw_payloads,
+            encrypted,
         })
     }
 
@@ -48,20 +138,134 @@ impl DataChunk {
         let mapping_location = self.mapping.roref.as_ptr() as usize;
         let offset_location = offset_real + mapping_location + HEADER_SIZE;
         let pointer = offset_location as *const u8;
-        let length = self.header.compressed_length as usize;
+        // recompress() may have shrunk the payload since self.header was read
+        let length = read_header(&self.mapping, self.offset).compressed_length as usize;
 
         let slice = unsafe { std::slice::from_raw_parts(pointer, length) };
 
         return Ok(slice);
     }
 
-    pub fn read(&self) -> UssResult<Vec<u8>> {
-        let compressed = self.read_compressed()?;
+    // encrypted payloads are never raw, even if they were stored uncompressed
+    pub fn is_raw(&self) -> bool {
+        let header = read_header(&self.mapping, self.offset);
+
+        self.raw_payloads
+            && !self.encrypted
+            && header.compressed_length == header.uncompressed_length
+    }
+
+    fn check_hash(&self, data: &[u8]) -> UssResult<()> {
+        if !crate::hasher::verify(&self.header.hash, data) {
+            return Err(UssError::Corruption(self.header.hash));
+        }
+
+        Ok(())
+    }
+
+    // decompresses into out, which must hold uncompressed_length bytes
+    pub fn read_into(
+        &self,
+        decompressors: &mut DecompressorCollection,
+        out: &mut [u8],
+    ) -> UssResult<usize> {
+        let length = self.decode_into(decompressors, out)?;
+
+        if self.verify {
+            self.check_hash(&out[..length])?;
+        }
+
+        Ok(length)
+    }
+
+    fn decode_into(
+        &self,
+        decompressors: &mut DecompressorCollection,
+        out: &mut [u8],
+    ) -> UssResult<usize> {
+        let stored = self.read_compressed()?;
         let outlen = self.header.uncompressed_length as usize;
-        let data = decompress(compressed, outlen)?;
+
+        if out.len() < outlen {
+            return Err(UssError::StaticError(
+                "DataChunk::read: output buffer is too small",
+            ));
+        }
+
+        let out = &mut out[..outlen];
+
+        let decrypted;
+
+        let compressed = match &self.key {
+            Some(key) => {
+                decrypted = key.decrypt(&self.header.hash, stored)?;
+                decrypted.as_slice()
+            }
+            None if self.encrypted => {
+                return Err(UssError::StaticError(
+                    "DataChunk::read: encrypted lake was loaded without a key",
+                ))
+            }
+            None => stored,
+        };
+
+        if self.raw_payloads && compressed.len() == outlen {
+            out.copy_from_slice(compressed);
+
+            return Ok(outlen);
+        }
+
+        if dictionary::is_zstd_frame(compressed) {
+            return match &self.dictionary {
+                Some(dictionary) => dictionary.decompress_into(compressed, out),
+                None => Err(UssError::StaticError(
+                    "DataChunk::read: chunk requires the lake's dictionary",
+                )),
+            };
+        }
+
+        decompressors.decompress_into(compressed, out)
+    }
+
+    // raw chunks are borrowed from the mapping, others decompressed into buffer
+    pub fn read_with<'a>(
+        &'a self,
+        decompressors: &mut DecompressorCollection,
+        buffer: &'a mut Vec<u8>,
+    ) -> UssResult<&'a [u8]> {
+        if self.is_raw() {
+            let data = self.read_compressed()?;
+
+            if self.verify {
+                self.check_hash(data)?;
+            }
+
+            return Ok(data);
+        }
+
+        buffer.resize(self.header.uncompressed_length as usize, 0);
+
+        let length = self.read_into(decompressors, buffer)?;
+
+        Ok(&buffer[..length])
+    }
+
+    pub fn read(&self) -> UssResult<Vec<u8>> {
+        let mut data = vec![0; self.header.uncompressed_length as usize];
+        let length = self.read_into(&mut DecompressorCollection::new(), &mut data)?;
+
+        data.truncate(length);
 
         return Ok(data);
     }
+
+    // re-hashes the stored data, regardless of the lake's verify setting
+    pub fn verify(&self) -> UssResult<()> {
+        let mut data = vec![0; self.header.uncompressed_length as usize];
+        let length = self.decode_into(&mut DecompressorCollection::new(), &mut data)?;
+
+        self.check_hash(&data[..length])
+    }
 }
 
 #[repr(C)]
@@ -74,8 +278,8 @@ struct DataLakeHeader {
     data_size: u32,
     // offset where data starts in 256-byte chunks
     data_offset: u32,
-    // next free 256-byte chunk
-    data_next: u32,
+    // next free 256-byte chunk, published by put() after the chunk is written
+    data_next: AtomicU32,
     // index_offset: *mut u32 = (hasher::checksum(hash) % index_mod) + index_offset_u32
     index_mod: u32,
     // max value of index_offset before overflow into data
@@ -84,19 +288,147 @@ struct DataLakeHeader {
     index_offset: u32,
     // index_offset << 6
     index_offset_u32: u32,
+    // hash of the zstd dictionary chunk, zeroed if the lake has none
+    dictionary: [u8; 50],
+    // LakeKey::check_value() of the encryption key, zeroed if unencrypted
+    key_check: [u8; 32],
+    // IndexFormat::to_header(), zeroed (linear) in lakes from before it
+    index_format: u32,
+    // entries in a bucketed index
+    index_used: AtomicU32,
+    // LAKE_FORMAT when created, zeroed in lakes from before raw payloads
+    format: u32,
+    // bumped by every rewrite of units below data_next, see generation()
+    generation: u32,
+    // unit offset of the chunk holding the named refs, zeroed if there are
+    // none; swapped atomically by set_ref(), see refs
+    refs: AtomicU32,
+    // HashAlgorithm::to_header() of the hashes put() makes, zeroed (legacy)
+    // in lakes from before it
+    hash_algorithm: u32,
+}
+
+impl DataLakeHeader {
+    // header for an empty lake of file_size bytes
+    fn for_file_size(file_size: u64, format: IndexFormat) -> DataLakeHeader {
+        let index_offset = 1;
+        let index_offset_u32 = 1 << 6;
+
+        let (index_mod, index_max, data_offset) = match format {
+            IndexFormat::Linear => {
+                let index_mod = sieve::get_le_prime((file_size >> 10) as u32);
+                let index_max = (index_offset + 1 + ((index_mod - 1) >> 6)) << 6;
+
+                // 1 (header size) + ceil(index_mod / (256 / 4))
+                (index_mod, index_max, 2 + ((index_mod - 1) >> 6))
+            }
+            IndexFormat::Bucketed => {
+                // as many entries as the linear index has slots, in buckets
+                let index_mod = sieve::get_le_prime(std::cmp::max(3, (file_size >> 13) as u32));
+                let index_max = index_offset_u32 + index_mod * index::BUCKET_U32S;
+
+                // 1 (header size) + ceil(index_mod / (256 / 64))
+                (index_mod, index_max, 2 + ((index_mod - 1) >> 2))
+            }
+        };
+
+        // in 256-byte chunks
+        let data_size = (file_size >> 8) as u32 - data_offset;
+
+        DataLakeHeader {
+            magic: b"DataLake".to_owned(),
+            file_size,
+            data_size,
+            data_offset,
+            data_next: AtomicU32::new(data_offset),
+            index_mod,
+            index_max,
+            index_offset,
+            index_offset_u32,
+            dictionary: [0; 50],
+            key_check: [0; 32],
+            index_format: format.to_header(),
+            index_used: AtomicU32::new(0),
+            format: LAKE_FORMAT,
+            generation: 0,
+            refs: AtomicU32::new(0),
+            hash_algorithm: 0,
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct DataLakeOptions {
+    pub profile: CompressionProfile,
+    // encrypts chunk payloads; must be given at create() to enable encryption
+    pub key: Option<[u8; 32]>,
+    // re-hash chunk data on every read and fail with UssError::Corruption
+    pub verify: bool,
+    pub cache: CacheOptions,
+    // keep a Bloom filter of stored hashes, built by scanning the data region
+    pub filter: bool,
+    // index layout of lakes made by create(); loaded lakes keep their own
+    pub index_format: IndexFormat,
+    // threads compressing for put_many(), 0 for one per CPU
+    pub workers: usize,
+    // hashes put() gives new chunks, Legacy if None; stored in the lake, so
+    // load() fails if given one other than the lake's. Chunks of any
+    // algorithm can be read.
+    pub hash_algorithm: Option<HashAlgorithm>,
 }
 
 pub struct DataLake {
-    data: Rc<MemoryMapping>,
-    chunks: HashMap<[u8; 50], DataChunk>,
+    data: Arc<MemoryMapping>,
+    chunks: ChunkCache,
+    filter: Option<HashFilter>,
     // if MemoryMapping is readonly, modifying header will lead to SIGSEGV
-    header: &'static mut DataLakeHeader,
+    header: NonNull<DataLakeHeader>,
     readonly: bool,
-    compressors: CompressorCollection,
+    // flocks of the locking protocol in mapping, released when the lake is
+    // dropped; readers only hold the shared one
+    shared_lock: std::fs::File,
+    _writer_lock: Option<std::fs::File>,
+    // serializes appends to the data region
+    writer: Mutex<()>,
+    // thread holding the writer lock through an open Transaction
+    transaction_thread: Mutex<Option<std::thread::ThreadId>>,
+    compressors: Mutex<CompressorCollection>,
+    decompressors: Mutex<DecompressorCollection>,
+    dictionary: Option<Arc<Dictionary>>,
+    key: Option<Arc<LakeKey>>,
+    encrypted: bool,
+    verify: bool,
+    profile: CompressionProfile,
+    workers: usize,
+    hash_algorithm: HashAlgorithm,
 }
 
+// Readers only touch the immutable mapping, the sharded cache and the
+// compressor and decompressor pools. The header is mutated through &mut self, except for
+// data_next, which is atomic and only advanced by put() under the writer lock.
+unsafe impl Send for DataLake {}
+unsafe impl Sync for DataLake {}
+
 impl DataLake {
     pub fn load(filename: &str, readonly: bool) -> UssResult<DataLake> {
+        DataLake::load_with_options(filename, readonly, DataLakeOptions::default())
+    }
+
+    pub fn load_with_options(
+        filename: &str,
+        readonly: bool,
+        options: DataLakeOptions,
+    ) -> UssResult<DataLake> {
+        options.profile.validate()?;
+
+        let writer_lock = if readonly {
+            None
+        } else {
+            Some(lock_writer(filename)?)
+        };
+
+        let shared_lock = lock_shared(filename)?;
+
         let data_map = if readonly {
             create_ro_mapping(filename)?
         } else {
@@ -105,7 +437,7 @@ impl DataLake {
 
         let header_ptr = data_map.roref.as_ptr() as *mut DataLakeHeader;
 
-        let header: &'static mut DataLakeHeader = match unsafe { header_ptr.as_mut() } {
+        let header = match NonNull::new(header_ptr) {
             Some(header) => header,
             None => {
                 return Err(UssError::StaticError(
@@ -114,16 +446,280 @@ impl DataLake {
             }
         };
 
-        Ok(DataLake {
-            data: Rc::from(data_map),
-            chunks: HashMap::new(),
+        let key_check = unsafe { header.as_ref() }.key_check;
+        let encrypted = key_check != [0; 32];
+        let hash_algorithm =
+            match HashAlgorithm::from_header(unsafe { header.as_ref() }.hash_algorithm) {
+                Some(algorithm) => algorithm,
+                None => {
+                    return Err(UssError::StaticError(
+                        "DataLake::load: unknown hash algorithm",
+                    ))
+                }
+            };
+
+        if options
+            .hash_algorithm
+            .is_some_and(|given| given != hash_algorithm)
+        {
+            return Err(UssError::StaticError(
+                "DataLake::load: lake uses a different hash algorithm",
+            ));
+        }
+        let key = options.key.map(LakeKey::new);
+
+        if let Some(key) = &key {
+            if !encrypted {
+                return Err(UssError::StaticError(
+                    "DataLake::load: key given for an unencrypted lake",
+                ));
+            }
+
+            if key.check_value() != key_check {
+                return Err(UssError::StaticError("DataLake::load: wrong key"));
+            }
+        }
+
+        let mut lake = DataLake {
+            data: Arc::from(data_map),
+            chunks: ChunkCache::new(options.cache),
+            filter: None,
             header,
             readonly,
-            compressors: CompressorCollection::new(),
-        })
+            shared_lock,
+            _writer_lock: writer_lock,
+            writer: Mutex::new(()),
+            transaction_thread: Mutex::new(None),
+            compressors: Mutex::new(CompressorCollection::with_profile(options.profile)),
+            decompressors: Mutex::new(DecompressorCollection::new()),
+            dictionary: None,
+            key: key.map(Arc::new),
+            encrypted,
+            verify: options.verify,
+            profile: options.profile,
+            workers: options.workers,
+            hash_algorithm,
+        };
+
+        if !readonly {
+            lake.discard_unpublished()?;
+        }
+
+        if options.filter {
+            lake.reset_filter()?;
+        }
+
+        if encrypted && lake.key.is_none() {
+            // without the key, only the index and chunk headers are usable
+            return Ok(lake);
+        }
+
+        lake.load_dictionary()?;
+
+        Ok(lake)
+    }
+
+    fn header(&self) -> &DataLakeHeader {
+        unsafe { self.header.as_ref() }
+    }
+
+    fn header_mut(&mut self) -> &mut DataLakeHeader {
+        unsafe { self.header.as_mut() }
+    }
+
+    fn data_next(&self) -> u32 {
+        self.header().data_next.load(Ordering::Acquire)
+    }
+
+    // Changes whenever recompress() or repair() may have rewritten units
+    // that were already published, so sidecars computed over the data
+    // region, like ParityFile, can tell they went stale.
+    pub fn generation(&self) -> u32 {
+        self.header().generation
+    }
+
+    fn bump_generation(&mut self) {
+        let header = self.header_mut();
+
+        header.generation = header.generation.wrapping_add(1);
+    }
+
+    fn clear_cache(&mut self) {
+        self.chunks.clear();
+    }
+
+    // rebuilds the filter from the data region, if the lake has one
+    fn reset_filter(&mut self) -> UssResult<()> {
+        let capacity = self.index_capacity() as usize;
+        let data_offset = self.header().data_offset;
+
+        self.filter = Some(HashFilter::new(capacity, data_offset));
+        self.filter_catch_up()?;
+
+        Ok(())
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.chunks.stats()
+    }
+
+    fn load_dictionary(&mut self) -> UssResult<()> {
+        let hash = self.header().dictionary;
+
+        if hash[0] == 0 {
+            return Ok(());
+        }
+
+        let chunk = match self.get(&hash) {
+            Some(chunk) => chunk,
+            None => {
+                return Err(UssError::StaticError(
+                    "DataLake::load: dictionary chunk is missing",
+                ))
+            }
+        };
+
+        let data = chunk.read()?;
+
+        self.dictionary = Some(Arc::new(Dictionary::new(
+            hash,
+            &data,
+            self.profile.zstd_level(),
+        )));
+        self.clear_cache();
+
+        Ok(())
+    }
+
+    // Picks up changes made by a writer in another process. New chunks are
+    // visible without this, since data_next and the index are read from the
+    // shared mapping; this remaps the file if its length changed, loads a
+    // newly trained dictionary and drops cached chunks, which heal() may have
+    // repointed. Returns whether the mapping or dictionary changed.
+    pub fn refresh(&mut self) -> UssResult<bool> {
+        if !self.readonly {
+            // we are the only writer, there is nothing to pick up
+            return Ok(false);
+        }
+
+        let mut changed = false;
+        let file_len = self.shared_lock.metadata().map_err(to_error)?.len();
+
+        if file_len != self.data.roref.len() as u64 {
+            if file_len < std::mem::size_of::<DataLakeHeader>() as u64 {
+                return Err(UssError::StaticError(
+                    "DataLake::refresh: lake file was truncated",
+                ));
+            }
+
+            let data = map_ro_file(&self.shared_lock)?;
+
+            self.header = match NonNull::new(data.roref.as_ptr() as *mut DataLakeHeader) {
+                Some(header) => header,
+                None => {
+                    return Err(UssError::StaticError(
+                        "DataLake::refresh: lake pointer is NULL",
+                    ))
+                }
+            };
+            self.data = Arc::new(data);
+
+            changed = true;
+        }
+
+        let dictionary = self.header().dictionary;
+        let loaded = self.dictionary.as_ref().map(|dictionary| dictionary.hash);
+
+        if dictionary[0] != 0
+            && loaded != Some(dictionary)
+            && (!self.encrypted || self.key.is_some())
+        {
+            self.load_dictionary()?;
+
+            changed = true;
+        }
+
+        self.clear_cache();
+
+        Ok(changed)
+    }
+
+    // held while published bytes are rewritten in place, see mapping
+    pub(super) fn lock_rewrite(&self) -> UssResult<RewriteLock> {
+        RewriteLock::new(&self.shared_lock)
+    }
+
+    // chunk at a known offset, set up to read with this lake's dictionary and key
+    fn chunk_at(&self, offset: u32) -> UssResult<DataChunk> {
+        let mut chunk = DataChunk::at(self.data.clone(), offset)?;
+
+        chunk.dictionary = self.dictionary.clone();
+        chunk.key = self.key.clone();
+        chunk.verify = self.verify;
+
+        Ok(chunk)
+    }
+
+    // offsets of all chunks in the data region, in storage order
+    pub fn chunk_offsets(&self) -> UssResult<Vec<u32>> {
+        Ok(self.iter().map(|chunk| chunk.offset).collect())
+    }
+
+    // trains a zstd dictionary on up to max_samples evenly spaced chunks;
+    // chunks stored afterwards are compressed with it
+    pub fn train_dictionary(
+        &mut self,
+        max_samples: usize,
+        max_size: usize,
+    ) -> UssResult<DataChunk> {
+        if self.readonly {
+            return Err(UssError::StaticError(
+                "Must not call train_dictionary() on readonly lake.",
+            ));
+        }
+
+        if self.dictionary.is_some() {
+            // existing zstd chunks would become unreadable with a new dictionary
+            return Err(UssError::StaticError(
+                "DataLake::train_dictionary: lake already has a dictionary",
+            ));
+        }
+
+        let offsets = self.chunk_offsets()?;
+        let step = std::cmp::max(1, offsets.len() / std::cmp::max(1, max_samples));
+        let mut samples: Vec<Vec<u8>> = Vec::with_capacity(max_samples);
+
+        for offset in offsets.iter().step_by(step).take(max_samples) {
+            samples.push(self.chunk_at(*offset)?.read()?);
+        }
+
+        let max_size = std::cmp::min(max_size, dictionary::DICTIONARY_MAX_SIZE);
+        let trained = dictionary::train(&samples, max_size)?;
+        let chunk = self.put(&trained)?;
+        let _rewrite = self.lock_rewrite()?;
+
+        self.header_mut().dictionary = chunk.header.hash;
+        self.dictionary = Some(Arc::new(Dictionary::new(
+            chunk.header.hash,
+            &trained,
+            self.profile.zstd_level(),
+        )));
+        self.clear_cache();
+
+        Ok(chunk)
     }
 
     pub fn create(file_name: &str, file_size: u64) -> UssResult<DataLake> {
+        DataLake::create_with_options(file_name, file_size, DataLakeOptions::default())
+    }
+
+    pub fn create_with_options(
+        file_name: &str,
+        file_size: u64,
+        options: DataLakeOptions,
+    ) -> UssResult<DataLake> {
+        options.profile.validate()?;
+
         if std::fs::metadata(file_name).is_ok() {
             return Err(UssError::DynamicError(format!(
                 "File {} already exists",
@@ -140,28 +736,13 @@ impl DataLake {
 
         file.set_len(file_size.into()).map_err(to_error)?;
 
-        let index_offset = 1;
-        let index_offset_u32 = 1 << 6;
-
-        let index_mod = sieve::get_le_prime((file_size >> 10) as u32);
-        let index_max = (index_offset + 1 + ((index_mod - 1) >> 6)) << 6;
-
-        // 1 (header size) + ceil(index_mod / (256 / 4))
-        let data_offset = 2 + ((index_mod - 1) >> 6);
-
-        // in 256-byte chunks
-        let data_size = (file_size >> 8) as u32 - data_offset;
-
         let header = DataLakeHeader {
-            magic: b"DataLake".to_owned(),
-            file_size,
-            data_size,
-            data_offset,
-            data_next: data_offset,
-            index_mod,
-            index_max,
-            index_offset,
-            index_offset_u32,
+            key_check: match options.key {
+                Some(key) => LakeKey::new(key).check_value(),
+                None => [0; 32],
+            },
+            hash_algorithm: options.hash_algorithm.unwrap_or_default().to_header(),
+            ..DataLakeHeader::for_file_size(file_size, options.index_format)
         };
 
         let header_ptr = &header as *const DataLakeHeader;
@@ -171,46 +752,309 @@ impl DataLake {
 
         file.write_all(header_slice).map_err(to_error)?;
 
-        return DataLake::load(file_name, false);
+        return DataLake::load_with_options(file_name, false, options);
     }
 
-    pub fn get_index_offset(&self, hash: &[u8; 50]) -> u32 {
-        let checksum = crate::hasher::checksum_u32(hash, 50);
+    pub fn set_verify(&mut self, verify: bool) {
+        self.verify = verify;
+        self.clear_cache();
+    }
 
-        return checksum % self.header.index_mod + self.header.index_offset_u32;
+    pub fn hash_algorithm(&self) -> HashAlgorithm {
+        self.hash_algorithm
     }
 
-    pub fn get(&mut self, hash: &[u8; 50]) -> Option<DataChunk> {
-        match self.chunks.get(hash) {
-            Some(val) => return Some(val.clone()),
-            None => {
-                let mut index_offset = self.get_index_offset(hash);
+    // the hash put() stores data under
+    pub fn hash_of(&self, data: &[u8]) -> [u8; 50] {
+        hash_with(self.hash_algorithm, data)
+    }
 
-                loop {
-                    let chunk_offset = self.data.read_u32(index_offset);
+    pub fn profile(&self) -> CompressionProfile {
+        self.profile
+    }
 
-                    if chunk_offset == 0 {
-                        return None;
-                    }
+    // affects chunks stored from now on, use recompress() for existing ones
+    pub fn set_profile(&mut self, profile: CompressionProfile) -> UssResult<()> {
+        profile.validate()?;
+
+        self.profile = profile;
+
+        if let Ok(compressors) = self.compressors.get_mut() {
+            *compressors = CompressorCollection::with_profile(profile);
+        }
+
+        if let Some(dictionary) = self.dictionary.take() {
+            let data = dictionary.data().to_vec();
+
+            self.dictionary = Some(Arc::new(Dictionary::new(
+                dictionary.hash,
+                &data,
+                profile.zstd_level(),
+            )));
+            self.clear_cache();
+        }
+
+        Ok(())
+    }
+
+    // Rewrites up to max_chunks cold chunks, starting at the chunk offset
+    // cursor, with the given profile. Chunks in the cache are considered hot
+    // and are skipped. Payloads are only replaced when they get smaller, so
+    // hashes and offsets stay valid; freed units are zeroed but not reused.
+    // The rewrite lock is held throughout, so readers in other processes wait;
+    // DataChunk handles taken earlier keep their old header.compressed_length,
+    // only their read methods see the new payload.
+    // Returns the cursor to resume from, or None at the end of the data region.
+    pub fn recompress(
+        &mut self,
+        profile: CompressionProfile,
+        cursor: u32,
+        max_chunks: usize,
+    ) -> UssResult<Option<u32>> {
+        if self.readonly {
+            return Err(UssError::StaticError(
+                "Must not call recompress() on readonly lake.",
+            ));
+        }
 
-                    let chunk = DataChunk::at(self.data.clone(), chunk_offset).ok()?;
+        profile.validate()?;
 
-                    if &chunk.header.hash != hash {
-                        index_offset += 1;
+        let _rewrite = self.lock_rewrite()?;
+        let mut compressors = CompressorCollection::with_profile(profile);
+        let mut offset = std::cmp::max(cursor, self.header().data_offset);
+        let mut visited = 0;
+        let mut rewritten = false;
 
-                        continue;
+        while offset < self.data_next() {
+            if visited >= max_chunks {
+                return Ok(Some(offset));
+            }
+
+            let chunk = self.chunk_at(offset)?;
+
+            if chunk.header.hash[0] == 0 {
+                offset += 1;
+                continue;
+            }
+
+            let next = offset + chunk.header.units();
+
+            visited += 1;
+
+            if self.chunks.contains(&chunk.header.hash) {
+                offset = next;
+                continue;
+            }
+
+            let data = chunk.read()?;
+
+            let compressed = match &self.dictionary {
+                // the dictionary chunk itself must stay readable without a dictionary
+                Some(dictionary) if dictionary.hash != chunk.header.hash => {
+                    dictionary.compress_with_level(&data, profile.zstd_level())?
+                }
+                _ => compressors.compress(&data)?,
+            };
+
+            let compressed =
+                self.encrypt_payload(&chunk.header.hash, self.encode_payload(compressed, &data))?;
+
+            if compressed.len() < chunk.header.compressed_length as usize {
+                if !rewritten {
+                    self.bump_generation();
+                    rewritten = true;
+                }
+
+                let mut map = match &self.data.owned_rw {
+                    Some(arc) => arc.lock().map_err(to_error)?,
+                    None => {
+                        return Err(UssError::StaticError(
+                            "recompress() called on read-only map",
+                        ))
                     }
+                };
+
+                let header = DataChunkHeader {
+                    compressed_length: compressed.len() as u16,
+                    ..chunk.header
+                };
+
+                let offset_bytes = offset_to_data_offset(offset);
+                let alloc_size = HEADER_SIZE + compressed.len();
+                let old_alloc_size = HEADER_SIZE + chunk.header.compressed_length as usize;
+
+                map[offset_bytes + HEADER_SIZE..offset_bytes + alloc_size]
+                    .copy_from_slice(&compressed);
 
-                    self.chunks.insert(hash.to_owned(), chunk.clone());
+                // freed units must read as empty when scanning the data region
+                map[offset_bytes + alloc_size..offset_bytes + old_alloc_size].fill(0);
 
-                    return Some(chunk);
+                unsafe {
+                    // header last, so the new length never covers stale bytes
+                    let header_ptr = map[offset_bytes..].as_mut_ptr() as *mut DataChunkHeader;
+
+                    header_ptr.copy_from(&header, 1);
                 }
             }
+
+            offset = next;
+        }
+
+        Ok(None)
+    }
+
+    // first index slot to probe for hash
+    pub fn get_index_offset(&self, hash: &[u8; 50]) -> u32 {
+        let checksum = crate::hasher::checksum_u32(hash, 50);
+        let home = checksum % self.header().index_mod;
+
+        return match self.index_format() {
+            IndexFormat::Linear => home + self.header().index_offset_u32,
+            IndexFormat::Bucketed => home * index::BUCKET_U32S + self.header().index_offset_u32,
+        };
+    }
+
+    // file size in 256-byte chunks, as actually mapped
+    fn mapped_units(&self) -> u32 {
+        (self.data.roref.len() >> 8) as u32
+    }
+
+    fn in_data_region(&self, offset: u32) -> bool {
+        offset >= self.header().data_offset
+            && offset < self.data_next()
+            && offset_to_data_offset(offset) + HEADER_SIZE <= self.data.roref.len()
+    }
+
+    pub fn get(&self, hash: &[u8; 50]) -> Option<DataChunk> {
+        if let Some(chunk) = self.chunks.get(hash) {
+            return Some(chunk);
+        }
+
+        if !self.may_contain(hash) {
+            return None;
+        }
+
+        let (_, chunk_offset) = self.probe(hash)?;
+        let chunk = self.chunk_at(chunk_offset).ok()?;
+
+        self.chunks.insert(&chunk);
+
+        return Some(chunk);
+    }
+
+    // runs f with a compressor from the pool, without holding the pool's lock
+    fn with_compressor<T>(
+        &self,
+        f: impl FnOnce(&mut CompressorCollection) -> UssResult<T>,
+    ) -> UssResult<T> {
+        let mut local = CompressorCollection::with_profile(self.profile);
+
+        match self.compressors.lock() {
+            Ok(mut pool) => local.push(pool.pop()),
+            Err(_) => return Err(UssError::MutexPoison),
+        };
+
+        let result = f(&mut local);
+
+        match self.compressors.lock() {
+            Ok(mut pool) => pool.push(local.pop()),
+            Err(_) => return Err(UssError::MutexPoison),
+        };
+
+        result
+    }
+
+    // runs f with a decompressor from the pool, without holding the pool's lock
+    fn with_decompressor<T>(
+        &self,
+        f: impl FnOnce(&mut DecompressorCollection) -> UssResult<T>,
+    ) -> UssResult<T> {
+        let mut local = DecompressorCollection::new();
+
+        match self.decompressors.lock() {
+            Ok(mut pool) => local.push(pool.pop()),
+            Err(_) => return Err(UssError::MutexPoison),
+        };
+
+        let result = f(&mut local);
+
+        match self.decompressors.lock() {
+            Ok(mut pool) => pool.push(local.pop()),
+            Err(_) => return Err(UssError::MutexPoison),
+        };
+
+        result
+    }
+
+    pub fn read<'a>(&self, chunk: &'a DataChunk, buffer: &'a mut Vec<u8>) -> UssResult<&'a [u8]> {
+        if chunk.is_raw() {
+            return chunk.read_with(&mut DecompressorCollection::new(), buffer);
+        }
+
+        self.decode_cached(chunk, buffer)
+    }
+
+    // decodes a compressed or encrypted chunk, going through the payload cache
+    fn decode_cached<'a>(&self, chunk: &DataChunk, buffer: &'a mut Vec<u8>) -> UssResult<&'a [u8]> {
+        if let Some(payload) = self.chunks.payload(&chunk.header.hash) {
+            buffer.clear();
+            buffer.extend_from_slice(&payload);
+
+            return Ok(buffer);
+        }
+
+        buffer.resize(chunk.header.uncompressed_length as usize, 0);
+
+        let length =
+            self.with_decompressor(|decompressors| chunk.read_into(decompressors, buffer))?;
+
+        self.chunks
+            .insert_payload(&chunk.header.hash, &buffer[..length]);
+
+        Ok(&buffer[..length])
+    }
+
+    // like get() + read(), but raw chunks are borrowed straight from the mapping
+    pub fn get_with<'a>(
+        &'a self,
+        hash: &[u8; 50],
+        buffer: &'a mut Vec<u8>,
+    ) -> UssResult<Option<&'a [u8]>> {
+        let chunk = match self.get(hash) {
+            Some(chunk) => chunk,
+            None => return Ok(None),
+        };
+
+        if chunk.is_raw() {
+            let offset_bytes = offset_to_data_offset(chunk.offset) + HEADER_SIZE;
+            let length = chunk.header.uncompressed_length as usize;
+            let data = self.data.get_ro_slice(offset_bytes, length);
+
+            if chunk.verify {
+                chunk.check_hash(data)?;
+            }
+
+            return Ok(Some(data));
+        }
+
+        Ok(Some(self.decode_cached(&chunk, buffer)?))
+    }
+
+    pub fn put(&self, data: &[u8]) -> UssResult<DataChunk> {
+        self.store_chunk(self.hash_of(data), data)
+    }
+
+    // Stores data under a hash made by any algorithm, as when copying chunks
+    // between lakes, so they keep the hash trees reference them by.
+    pub fn put_hashed(&self, hash: &[u8; 50], data: &[u8]) -> UssResult<DataChunk> {
+        if !crate::hasher::verify(hash, data) {
+            return Err(UssError::Corruption(*hash));
         }
+
+        self.store_chunk(*hash, data)
     }
 
-    pub fn put(&mut self, data: &[u8]) -> UssResult<DataChunk> {
-        let hash = super::hasher::hash(data);
+    fn store_chunk(&self, hash: [u8; 50], data: &[u8]) -> UssResult<DataChunk> {
         let existing = self.get(&hash);
 
         match existing {
@@ -224,66 +1068,488 @@ impl DataLake {
             ));
         }
 
-        let mut map = match &self.data.owned_rw {
-            Some(arc) => arc.lock().map_err(to_error)?,
-            None => return Err(UssError::StaticError("put() called on read-only map")),
-        };
+        // compressed before locking, so only the append is serialized
+        let (header, payload) = self.encode_chunk(hash, data)?;
+        let _writer = self.lock_appends()?;
 
-        let compressed = self.compressors.compress(data)?;
+        // another writer may have stored it while we compressed or waited
+        if let Some(chunk) = self.get(&hash) {
+            return Ok(chunk);
+        }
+
+        self.append_chunk(&mut *self.lock_map()?, header, &payload)
+    }
+
+    // writes and publishes a chunk at data_next; needs the writer lock
+    fn append_chunk(
+        &self,
+        map: &mut memmap::MmapMut,
+        header: DataChunkHeader,
+        payload: &[u8],
+    ) -> UssResult<DataChunk> {
+        let offset = self.data_next();
+        let units = self.write_chunk(map, offset, &header, payload)?;
+
+        // publish data_next before the index slot, so readers that find the
+        // slot also see the chunk as part of the data region
+        self.header()
+            .data_next
+            .store(offset + units, Ordering::Release);
+
+        self.index_insert(map, &header.hash, offset)?;
+        self.filter_insert(&header.hash, offset, units);
+
+        Ok(self.written_chunk(header, offset))
+    }
 
-        let uncompressed_length = data.len() as u16;
-        let compressed_length = compressed.len() as u16;
+    // the writer lock, or an error instead of a deadlock if this thread
+    // already holds it through a transaction
+    fn lock_appends(&self) -> UssResult<std::sync::MutexGuard<'_, ()>> {
+        let thread = *self
+            .transaction_thread
+            .lock()
+            .map_err(|_| UssError::MutexPoison)?;
+
+        if thread == Some(std::thread::current().id()) {
+            return Err(UssError::StaticError(
+                "DataLake: this thread has a transaction open, put through it",
+            ));
+        }
+
+        self.writer.lock().map_err(|_| UssError::MutexPoison)
+    }
+
+    fn lock_map(&self) -> UssResult<std::sync::MutexGuard<'_, memmap::MmapMut>> {
+        match &self.data.owned_rw {
+            Some(arc) => arc.lock().map_err(to_error),
+            None => Err(UssError::StaticError("put() called on read-only map")),
+        }
+    }
+
+    // compresses and encrypts data into a header and the payload to store
+    fn encode_chunk(&self, hash: [u8; 50], data: &[u8]) -> UssResult<(DataChunkHeader, Vec<u8>)> {
+        let compressed = match &self.dictionary {
+            Some(dictionary) => dictionary.compress(data)?,
+            None => self.with_compressor(|compressors| compressors.compress(data))?,
+        };
+
+        let payload = self.encrypt_payload(&hash, self.encode_payload(compressed, data))?;
 
         let header = DataChunkHeader {
             hash,
-            uncompressed_length,
-            compressed_length,
+            uncompressed_length: data.len() as u16,
+            compressed_length: payload.len() as u16,
         };
 
-        let offset = self.header.data_next;
+        Ok((header, payload))
+    }
+
+    // writes a chunk at offset without publishing it, returns its size in units
+    fn write_chunk(
+        &self,
+        map: &mut memmap::MmapMut,
+        offset: u32,
+        header: &DataChunkHeader,
+        payload: &[u8],
+    ) -> UssResult<u32> {
         let offset_bytes = offset_to_data_offset(offset);
-        let alloc_size: usize = HEADER_SIZE + compressed_length as usize;
+        let alloc_size: usize = HEADER_SIZE + payload.len();
+        let units = header.units();
+
+        if offset + units > self.mapped_units() {
+            return Err(UssError::StaticError("DataLake ran out of space."));
+        }
 
         unsafe {
             // write header
             let write_location = &mut map[offset_bytes..offset_bytes + alloc_size];
             let header_ptr = write_location.as_mut_ptr() as *mut DataChunkHeader;
 
-            header_ptr.copy_from(&header, 1);
+            header_ptr.copy_from(header, 1);
         }
 
         let write_location = &mut map[offset_bytes + HEADER_SIZE..offset_bytes + alloc_size];
 
-        write_location.copy_from_slice(&compressed);
+        write_location.copy_from_slice(payload);
 
-        (*self.header).data_next += ((alloc_size - 1) >> 8) as u32 + 1;
+        Ok(units)
+    }
 
-        {
-            // add to index
-            let mut index_offset = self.get_index_offset(&hash);
+    fn written_chunk(&self, header: DataChunkHeader, offset: u32) -> DataChunk {
+        DataChunk {
+            header,
+            mapping: self.data.clone(),
+            offset,
+            dictionary: self.dictionary.clone(),
+            key: self.key.clone(),
+            verify: self.verify,
+            raw_payloads: self.raw_payloads(),
+            encrypted: self.encrypted,
+        }
+    }
 
-            loop {
-                if index_offset > self.header.index_max {
-                    return Err(UssError::StaticError("DataLake index ran out of space."));
-                }
+    // see LAKE_FORMAT_RAW
+    pub fn raw_payloads(&self) -> bool {
+        self.header().format >= LAKE_FORMAT_RAW
+    }
+
+    fn encode_payload(&self, compressed: Vec<u8>, data: &[u8]) -> Vec<u8> {
+        encode_payload(compressed, data, self.raw_payloads())
+    }
+
+    fn encrypt_payload(&self, hash: &[u8; 50], payload: Vec<u8>) -> UssResult<Vec<u8>> {
+        match &self.key {
+            Some(key) => key.encrypt(hash, &payload),
+            None if self.encrypted => Err(UssError::StaticError(
+                "DataLake: encrypted lake was loaded without a key",
+            )),
+            None => Ok(payload),
+        }
+    }
+}
+
+// fresh path in the temp directory for a test's lake files
+#[cfg(test)]
+pub(crate) fn test_path(name: &str) -> String {
+    let path = std::env::temp_dir().join(format!("uss-test-{}-{}", std::process::id(), name));
+
+    let _ = std::fs::remove_file(&path);
+    let _ = std::fs::remove_dir_all(&path);
+
+    path.to_string_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-                if self.data.read_u32(index_offset) != 0 {
-                    index_offset += 1;
-                    continue;
+    // pseudo-random bytes after a compressible run of prefix bytes
+    pub(super) fn noise(length: usize, prefix: usize) -> Vec<u8> {
+        let mut state: u32 = 7;
+
+        (0..length)
+            .map(|i| match i < prefix {
+                true => b'a',
+                false => {
+                    state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                    (state >> 16) as u8
                 }
+            })
+            .collect()
+    }
+
+    // data whose deflate output is exactly as long as the data
+    pub(super) fn equal_length_data() -> Vec<u8> {
+        overhead_length_data(0)
+    }
+
+    // data whose deflate output plus overhead bytes is exactly as long as the
+    // data, e.g. the payload of an encrypted lake
+    pub(super) fn overhead_length_data(overhead: usize) -> Vec<u8> {
+        (0..200)
+            .map(|prefix| noise(200, prefix))
+            .find(|data| crate::compression::compress(data).unwrap().len() + overhead == data.len())
+            .expect("no data with an equal-length payload")
+    }
+
+    #[test]
+    fn one_writer_and_no_rewrites_under_readers() {
+        let path = test_path("locking.lake");
+        let mut writer = DataLake::create(&path, 1 << 20).unwrap();
+
+        assert!(DataLake::load(&path, false).is_err());
+
+        let reader = DataLake::load(&path, true).unwrap();
+        let hash = writer.put(b"visible to readers").unwrap().header.hash;
+
+        assert_eq!(
+            reader.get(&hash).unwrap().read().unwrap(),
+            b"visible to readers"
+        );
+        assert!(writer.repair().is_err());
+
+        drop(reader);
+
+        writer.repair().unwrap();
+        drop(writer);
+
+        assert!(DataLake::load(&path, false).is_ok());
+    }
 
-                let map_offset = (index_offset as usize) << 2;
+    #[test]
+    fn raw_payloads_round_trip() {
+        let path = test_path("raw-payloads.lake");
+        let data = noise(200, 0);
 
-                map[map_offset..map_offset + 4].copy_from_slice(&offset.to_le_bytes());
+        {
+            let lake = DataLake::create(&path, 1 << 20).unwrap();
+            let chunk = lake.put(&data).unwrap();
+
+            assert!(lake.raw_payloads());
+            assert!(chunk.is_raw());
+        }
+
+        let lake = DataLake::load(&path, true).unwrap();
+        let mut buffer = Vec::new();
+        let hash = crate::hasher::hash(&data);
+
+        assert_eq!(lake.get(&hash).unwrap().read().unwrap(), data);
+        assert_eq!(
+            lake.get_with(&hash, &mut buffer).unwrap().unwrap(),
+            &data[..]
+        );
+    }
+
+    #[test]
+    fn legacy_format_keeps_compressor_output() {
+        let path = test_path("legacy-format.lake");
+        let longer = noise(200, 0);
+        let equal = equal_length_data();
+
+        {
+            let mut lake = DataLake::create(&path, 1 << 20).unwrap();
+
+            lake.header_mut().format = 0;
+
+            let chunk = lake.put(&longer).unwrap();
+
+            assert!(chunk.header.compressed_length > chunk.header.uncompressed_length);
+
+            let chunk = lake.put(&equal).unwrap();
+
+            assert_eq!(
+                chunk.header.compressed_length,
+                chunk.header.uncompressed_length
+            );
+            assert!(!chunk.is_raw());
+        }
 
-                break;
+        let lake = DataLake::load(&path, false).unwrap();
+        let mut buffer = Vec::new();
+
+        assert!(!lake.raw_payloads());
+
+        for data in [&longer, &equal] {
+            let hash = crate::hasher::hash(data);
+
+            assert_eq!(&lake.get(&hash).unwrap().read().unwrap(), data);
+            assert_eq!(
+                lake.get_with(&hash, &mut buffer).unwrap().unwrap(),
+                &data[..]
+            );
+        }
+
+        assert!(lake.fsck().unwrap().is_clean());
+    }
+
+    #[test]
+    fn trained_dictionary_survives_reload() {
+        let path = test_path("trained-dictionary.lake");
+        let record = |i: usize| {
+            format!(
+                "{{\"id\": {}, \"name\": \"user {}\", \"email\": \"user{}@example.com\", \"active\": {}}}",
+                i,
+                i * 7,
+                i * 13,
+                i % 3 == 0
+            )
+            .repeat(4)
+            .into_bytes()
+        };
+        let hashes: Vec<[u8; 50]>;
+
+        {
+            let mut lake = DataLake::create(&path, 1 << 22).unwrap();
+
+            for i in 0..256 {
+                lake.put(&record(i)).unwrap();
             }
+
+            lake.train_dictionary(256, 4096).unwrap();
+
+            // a second dictionary would strand chunks compressed with the first
+            assert!(lake.train_dictionary(256, 4096).is_err());
+
+            hashes = (1000..1032)
+                .map(|i| {
+                    let chunk = lake.put(&record(i)).unwrap();
+
+                    assert!(dictionary::is_zstd_frame(chunk.read_compressed().unwrap()));
+
+                    chunk.header.hash
+                })
+                .collect();
         }
 
-        Ok(DataChunk {
-            header,
-            mapping: self.data.clone(),
-            offset,
-        })
+        let lake = DataLake::load(&path, true).unwrap();
+        let mut buffer = Vec::new();
+
+        for (i, hash) in (1000..1032).zip(&hashes) {
+            let chunk = lake.get(hash).unwrap();
+
+            assert!(dictionary::is_zstd_frame(chunk.read_compressed().unwrap()));
+            assert_eq!(chunk.read().unwrap(), record(i));
+            assert_eq!(
+                lake.get_with(hash, &mut buffer).unwrap().unwrap(),
+                &record(i)[..]
+            );
+        }
+    }
+
+    #[test]
+    fn recompress_round_trip() {
+        let path = test_path("recompress.lake");
+        let record = |i: usize| {
+            (0..40)
+                .map(|j| format!("row {} of {}: value {} ", j, i, (i * j) % 17))
+                .collect::<String>()
+                .into_bytes()
+        };
+        let options = DataLakeOptions {
+            profile: CompressionProfile::Fast,
+            ..Default::default()
+        };
+        let before: Vec<(DataChunk, usize)>;
+
+        {
+            let lake = DataLake::create_with_options(&path, 1 << 20, options).unwrap();
+
+            before = (0..32)
+                .map(|i| {
+                    let chunk = lake.put(&record(i)).unwrap();
+                    let length = chunk.header.compressed_length as usize;
+
+                    (chunk, length)
+                })
+                .collect();
+        }
+
+        // a fresh handle, so no chunk is cached and skipped as hot
+        let mut lake = DataLake::load(&path, false).unwrap();
+        let mut cursor = Some(0);
+
+        assert!(lake
+            .recompress(CompressionProfile::Level(13), 0, usize::MAX)
+            .is_err());
+
+        while let Some(next) = cursor {
+            cursor = lake.recompress(CompressionProfile::Max, next, 8).unwrap();
+        }
+
+        let mut shrunk = 0;
+
+        for (i, (chunk, length)) in before.iter().enumerate() {
+            let stored = lake.get(&chunk.header.hash).unwrap();
+
+            assert!((stored.header.compressed_length as usize) <= *length);
+            assert_eq!(stored.read().unwrap(), record(i));
+
+            if (stored.header.compressed_length as usize) < *length {
+                shrunk += 1;
+            }
+        }
+
+        assert!(shrunk > 0);
+        assert!(lake.fsck().unwrap().is_clean());
+
+        drop(lake);
+
+        let lake = DataLake::load(&path, true).unwrap();
+
+        for (i, (chunk, _)) in before.iter().enumerate() {
+            assert_eq!(
+                lake.get(&chunk.header.hash).unwrap().read().unwrap(),
+                record(i)
+            );
+        }
+    }
+
+    #[test]
+    fn out_of_range_levels_are_rejected() {
+        let path = test_path("profile-levels.lake");
+        let options = DataLakeOptions {
+            profile: CompressionProfile::Level(i32::MAX),
+            ..Default::default()
+        };
+
+        assert!(DataLake::create_with_options(&path, 1 << 20, options).is_err());
+        assert!(std::fs::metadata(&path).is_err());
+
+        let mut lake = DataLake::create(&path, 1 << 20).unwrap();
+
+        assert!(lake.set_profile(CompressionProfile::Level(-1)).is_err());
+        assert_eq!(lake.profile(), CompressionProfile::Max);
+
+        lake.set_profile(CompressionProfile::Level(12)).unwrap();
+
+        assert_eq!(CompressionProfile::Level(i32::MAX).zstd_level(), 19);
+        assert_eq!(CompressionProfile::Level(0).zstd_level(), 1);
+    }
+
+    #[test]
+    fn refresh_remaps_a_resized_file() {
+        let path = test_path("refresh-remap.lake");
+        let writer = DataLake::create(&path, 1 << 20).unwrap();
+        let mut reader = DataLake::load(&path, true).unwrap();
+        let hash = writer.put(b"mapped before and after").unwrap().header.hash;
+
+        assert!(!reader.refresh().unwrap());
+
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(1 << 21)
+            .unwrap();
+
+        assert!(reader.refresh().unwrap());
+        assert_eq!(reader.data.roref.len(), 1 << 21);
+        assert_eq!(
+            reader.get(&hash).unwrap().read().unwrap(),
+            b"mapped before and after"
+        );
+    }
+
+    #[test]
+    fn hash_algorithm_is_stored_in_the_header() {
+        let path = test_path("stored-algorithm.lake");
+        let options = DataLakeOptions {
+            hash_algorithm: Some(HashAlgorithm::Blake3),
+            ..Default::default()
+        };
+        let hash = DataLake::create_with_options(&path, 1 << 20, options)
+            .unwrap()
+            .put(b"tagged before reload")
+            .unwrap()
+            .header
+            .hash;
+
+        // no algorithm given, the lake's own is used
+        let lake = DataLake::load(&path, false).unwrap();
+
+        assert_eq!(lake.hash_algorithm(), HashAlgorithm::Blake3);
+        assert_eq!(
+            hash,
+            hash_with(HashAlgorithm::Blake3, b"tagged before reload")
+        );
+        assert_eq!(
+            lake.put(b"tagged after reload").unwrap().header.hash,
+            hash_with(HashAlgorithm::Blake3, b"tagged after reload")
+        );
+
+        drop(lake);
+
+        let legacy = DataLakeOptions {
+            hash_algorithm: Some(HashAlgorithm::Legacy),
+            ..Default::default()
+        };
+
+        assert!(DataLake::load_with_options(&path, true, legacy).is_err());
+        assert_eq!(
+            DataLake::create(&test_path("legacy-algorithm.lake"), 1 << 20)
+                .unwrap()
+                .hash_algorithm(),
+            HashAlgorithm::Legacy
+        );
     }
 }
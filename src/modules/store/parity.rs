@@ -0,0 +1,682 @@
+use super::*;
+use serde::Serialize;
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom},
+};
+
+const PARITY_MAGIC: &[u8; 8] = b"DLParity";
+const PARITY_HEADER_SIZE: u64 = 256;
+const UNIT: usize = 256;
+
+// GF(2^8) with the 0x11d polynomial
+struct Galois {
+    exp: [u8; 512],
+    log: [u8; 256],
+}
+
+const fn galois() -> Galois {
+    let mut exp = [0u8; 512];
+    let mut log = [0u8; 256];
+    let mut value: u32 = 1;
+    let mut i = 0;
+
+    while i < 255 {
+        exp[i] = value as u8;
+        exp[i + 255] = value as u8;
+        log[value as usize] = i as u8;
+
+        value <<= 1;
+
+        if value & 0x100 != 0 {
+            value ^= 0x11d;
+        }
+
+        i += 1;
+    }
+
+    Galois { exp, log }
+}
+
+const GF: Galois = galois();
+
+fn gf_mul(a: u8, b: u8) -> u8 {
+    if a == 0 || b == 0 {
+        return 0;
+    }
+
+    GF.exp[GF.log[a as usize] as usize + GF.log[b as usize] as usize]
+}
+
+fn gf_inv(a: u8) -> u8 {
+    GF.exp[255 - GF.log[a as usize] as usize]
+}
+
+// target ^= factor * source
+fn gf_mul_add(target: &mut [u8], source: &[u8], factor: u8) {
+    if factor == 0 {
+        return;
+    }
+
+    for (t, &s) in target.iter_mut().zip(source) {
+        *t ^= gf_mul(s, factor);
+    }
+}
+
+// Cauchy matrix entry for parity row i and data column j; every square
+// submatrix is invertible, so any parity_units lost data units can be solved
+fn cauchy(parity_units: u32, i: u32, j: u32) -> u8 {
+    gf_inv((i ^ (parity_units + j)) as u8)
+}
+
+// inverts a square matrix in place, false if it is singular
+fn gf_invert(matrix: &mut [Vec<u8>]) -> bool {
+    let n = matrix.len();
+    let mut inverse: Vec<Vec<u8>> = (0..n)
+        .map(|row| (0..n).map(|col| (row == col) as u8).collect())
+        .collect();
+
+    for col in 0..n {
+        let pivot = match (col..n).find(|&row| matrix[row][col] != 0) {
+            Some(pivot) => pivot,
+            None => return false,
+        };
+
+        matrix.swap(col, pivot);
+        inverse.swap(col, pivot);
+
+        let scale = gf_inv(matrix[col][col]);
+
+        for k in 0..n {
+            matrix[col][k] = gf_mul(matrix[col][k], scale);
+            inverse[col][k] = gf_mul(inverse[col][k], scale);
+        }
+
+        for row in 0..n {
+            let factor = matrix[row][col];
+
+            if row == col || factor == 0 {
+                continue;
+            }
+
+            for k in 0..n {
+                matrix[row][k] ^= gf_mul(factor, matrix[col][k]);
+                inverse[row][k] ^= gf_mul(factor, inverse[col][k]);
+            }
+        }
+    }
+
+    matrix.clone_from_slice(&inverse);
+
+    true
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct ParityOptions {
+    // 256-byte data units per group
+    pub data_units: u32,
+    // parity units per group, the number of bad units a group survives
+    pub parity_units: u32,
+}
+
+impl Default for ParityOptions {
+    fn default() -> Self {
+        ParityOptions {
+            data_units: 16,
+            parity_units: 2,
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ParityScrubReport {
+    pub groups: usize,
+    // data units whose checksum didn't match
+    pub bad_units: usize,
+    pub repaired_units: usize,
+    // parity units rewritten in the sidecar
+    pub repaired_parity: usize,
+    // first unit of each group with more bad units than it has parity for
+    pub unrecoverable: Vec<u32>,
+}
+
+// One group of data units as read from the lake, with what the sidecar
+// holds for it.
+struct Group {
+    data: Vec<Vec<u8>>,
+    // units at or past covered read as zeros and are never checked
+    checked: Vec<bool>,
+    parity: Vec<Vec<u8>>,
+    data_checksums: Vec<u32>,
+    parity_checksums: Vec<u32>,
+}
+
+// Sidecar file with Reed-Solomon parity over the data region of a lake.
+// Every data_units consecutive 256-byte units form a group with
+// parity_units parity units, plus a checksum per unit that tells scrub()
+// which units are bad. Layout: a 256-byte header (magic, data_units,
+// parity_units, data_offset, covered, generation) and one record per group
+// holding its parity units, then the checksums of its data and parity units.
+//
+// Units rewritten by recompress() or repair() would read as corrupt, and
+// scrub() would roll them back. Those bump the lake's generation, and
+// update() and scrub() refuse to run until rebuild() has caught up with it.
+pub struct ParityFile {
+    file: File,
+    options: ParityOptions,
+    data_offset: u32,
+    // data units covered since data_offset, i.e. data_next at the last update
+    covered: u32,
+    // DataLake::generation() at the last rebuild
+    generation: u32,
+}
+
+fn check_options(options: &ParityOptions) -> UssResult<()> {
+    if options.data_units == 0
+        || options.parity_units == 0
+        || options.data_units + options.parity_units > 256
+    {
+        return Err(UssError::StaticError(
+            "ParityFile: need 1 to 255 data and parity units, at most 256 together",
+        ));
+    }
+
+    Ok(())
+}
+
+impl ParityFile {
+    pub fn create(path: &str, lake: &DataLake, options: ParityOptions) -> UssResult<ParityFile> {
+        check_options(&options)?;
+
+        // never clobbers a sidecar, which may belong to another lake; an
+        // existing one is brought up to date with open() and rebuild()
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .map_err(to_error)?;
+
+        let mut parity = ParityFile {
+            file,
+            options,
+            data_offset: lake.header().data_offset,
+            covered: 0,
+            generation: lake.generation(),
+        };
+
+        parity.write_header()?;
+        parity.update(lake)?;
+
+        Ok(parity)
+    }
+
+    pub fn open(path: &str, lake: &DataLake) -> UssResult<ParityFile> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(to_error)?;
+
+        let mut header = [0u8; 28];
+
+        file.read_exact(&mut header).map_err(to_error)?;
+
+        if &header[..8] != PARITY_MAGIC {
+            return Err(UssError::StaticError("ParityFile: not a parity file"));
+        }
+
+        let field =
+            |i: usize| u32::from_le_bytes([header[i], header[i + 1], header[i + 2], header[i + 3]]);
+
+        let parity = ParityFile {
+            file,
+            options: ParityOptions {
+                data_units: field(8),
+                parity_units: field(12),
+            },
+            data_offset: field(16),
+            covered: field(20),
+            generation: field(24),
+        };
+
+        // a damaged header must not divide by zero or index past a group
+        check_options(&parity.options)?;
+
+        if parity.data_offset != lake.header().data_offset {
+            return Err(UssError::StaticError(
+                "ParityFile: parity file belongs to a different lake layout",
+            ));
+        }
+
+        Ok(parity)
+    }
+
+    pub fn options(&self) -> ParityOptions {
+        self.options
+    }
+
+    fn write_header(&mut self) -> UssResult<()> {
+        let mut header = [0u8; PARITY_HEADER_SIZE as usize];
+
+        header[..8].copy_from_slice(PARITY_MAGIC);
+        header[8..12].copy_from_slice(&self.options.data_units.to_le_bytes());
+        header[12..16].copy_from_slice(&self.options.parity_units.to_le_bytes());
+        header[16..20].copy_from_slice(&self.data_offset.to_le_bytes());
+        header[20..24].copy_from_slice(&self.covered.to_le_bytes());
+        header[24..28].copy_from_slice(&self.generation.to_le_bytes());
+
+        self.write_at(0, &header)
+    }
+
+    fn write_at(&mut self, position: u64, bytes: &[u8]) -> UssResult<()> {
+        self.file
+            .seek(SeekFrom::Start(position))
+            .map_err(to_error)?;
+        self.file.write_all(bytes).map_err(to_error)
+    }
+
+    fn record_size(&self) -> u64 {
+        let units = self.options.data_units + self.options.parity_units;
+
+        (self.options.parity_units as usize * UNIT + units as usize * 4) as u64
+    }
+
+    fn record_position(&self, group: u32) -> u64 {
+        PARITY_HEADER_SIZE + group as u64 * self.record_size()
+    }
+
+    fn group_start(&self, group: u32) -> u32 {
+        self.data_offset + group * self.options.data_units
+    }
+
+    fn read_units(&self, lake: &DataLake, group: u32) -> (Vec<Vec<u8>>, Vec<bool>) {
+        let start = self.group_start(group);
+        let end = self.data_offset + self.covered;
+        let mut data = Vec::with_capacity(self.options.data_units as usize);
+        let mut checked = Vec::with_capacity(self.options.data_units as usize);
+
+        for unit in start..start + self.options.data_units {
+            if unit < end && unit < lake.mapped_units() {
+                data.push(
+                    lake.data
+                        .get_ro_slice(offset_to_data_offset(unit), UNIT)
+                        .to_vec(),
+                );
+                checked.push(true);
+            } else {
+                data.push(vec![0; UNIT]);
+                checked.push(false);
+            }
+        }
+
+        (data, checked)
+    }
+
+    fn encode(&self, data: &[Vec<u8>]) -> Vec<Vec<u8>> {
+        (0..self.options.parity_units)
+            .map(|i| {
+                let mut parity = vec![0; UNIT];
+
+                for (j, unit) in data.iter().enumerate() {
+                    gf_mul_add(
+                        &mut parity,
+                        unit,
+                        cauchy(self.options.parity_units, i, j as u32),
+                    );
+                }
+
+                parity
+            })
+            .collect()
+    }
+
+    fn write_group(&mut self, group: u32, data: &[Vec<u8>], parity: &[Vec<u8>]) -> UssResult<()> {
+        let mut record = Vec::with_capacity(self.record_size() as usize);
+
+        for unit in parity.iter() {
+            record.extend_from_slice(unit);
+        }
+
+        for unit in data.iter().chain(parity.iter()) {
+            record.extend_from_slice(&crate::hasher::checksum_u32(unit, UNIT as u32).to_le_bytes());
+        }
+
+        self.write_at(self.record_position(group), &record)
+    }
+
+    fn read_group(&mut self, lake: &DataLake, group: u32) -> UssResult<Group> {
+        let (data, checked) = self.read_units(lake, group);
+        let mut record = vec![0; self.record_size() as usize];
+
+        self.file
+            .seek(SeekFrom::Start(self.record_position(group)))
+            .map_err(to_error)?;
+        self.file.read_exact(&mut record).map_err(to_error)?;
+
+        let parity_bytes = self.options.parity_units as usize * UNIT;
+        let checksums: Vec<u32> = record[parity_bytes..]
+            .chunks_exact(4)
+            .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+            .collect();
+
+        Ok(Group {
+            data,
+            checked,
+            parity: record[..parity_bytes]
+                .chunks_exact(UNIT)
+                .map(|unit| unit.to_vec())
+                .collect(),
+            data_checksums: checksums[..self.options.data_units as usize].to_vec(),
+            parity_checksums: checksums[self.options.data_units as usize..].to_vec(),
+        })
+    }
+
+    // Adds parity for units written since the last update; the group that
+    // was only partly filled then is recomputed.
+    pub fn update(&mut self, lake: &DataLake) -> UssResult<u32> {
+        self.check_generation(lake)?;
+
+        let end =
+            std::cmp::min(lake.data_next(), lake.mapped_units()).saturating_sub(self.data_offset);
+
+        if end <= self.covered {
+            return Ok(0);
+        }
+
+        let first = self.covered / self.options.data_units;
+        let last = end.div_ceil(self.options.data_units);
+
+        self.covered = end;
+
+        for group in first..last {
+            let (data, _) = self.read_units(lake, group);
+            let parity = self.encode(&data);
+
+            self.write_group(group, &data, &parity)?;
+        }
+
+        self.write_header()?;
+
+        Ok(last - first)
+    }
+
+    // recomputes all parity, e.g. after units were rewritten in place
+    pub fn rebuild(&mut self, lake: &DataLake) -> UssResult<u32> {
+        self.covered = 0;
+        self.generation = lake.generation();
+        self.file.set_len(PARITY_HEADER_SIZE).map_err(to_error)?;
+
+        self.update(lake)
+    }
+
+    // Checks every covered unit against its checksum and reconstructs bad
+    // ones from the parity. A readonly lake is only checked.
+    pub fn scrub(&mut self, lake: &mut DataLake) -> UssResult<ParityScrubReport> {
+        self.check_generation(lake)?;
+
+        let mut report = ParityScrubReport::default();
+        let groups = self.covered.div_ceil(self.options.data_units);
+
+        for group in 0..groups {
+            let mut current = self.read_group(lake, group)?;
+
+            report.groups += 1;
+
+            let bad_data: Vec<usize> = (0..current.data.len())
+                .filter(|&j| {
+                    current.checked[j]
+                        && crate::hasher::checksum_u32(&current.data[j], UNIT as u32)
+                            != current.data_checksums[j]
+                })
+                .collect();
+
+            let good_parity: Vec<usize> = (0..current.parity.len())
+                .filter(|&i| {
+                    crate::hasher::checksum_u32(&current.parity[i], UNIT as u32)
+                        == current.parity_checksums[i]
+                })
+                .collect();
+
+            report.bad_units += bad_data.len();
+
+            if bad_data.len() > good_parity.len() {
+                report.unrecoverable.push(self.group_start(group));
+                continue;
+            }
+
+            if !bad_data.is_empty() {
+                if lake.readonly {
+                    continue;
+                }
+
+                if !self.reconstruct(&mut current, &bad_data, &good_parity) {
+                    report.unrecoverable.push(self.group_start(group));
+                    continue;
+                }
+
+                let _rewrite = lake.lock_rewrite()?;
+                let mut map = lake.lock_map()?;
+
+                for &j in bad_data.iter() {
+                    let start = offset_to_data_offset(self.group_start(group) + j as u32);
+
+                    map[start..start + UNIT].copy_from_slice(&current.data[j]);
+                }
+
+                report.repaired_units += bad_data.len();
+            }
+
+            if good_parity.len() < current.parity.len() {
+                let parity = self.encode(&current.data);
+
+                self.write_group(group, &current.data, &parity)?;
+                report.repaired_parity += current.parity.len() - good_parity.len();
+            }
+        }
+
+        if report.repaired_units > 0 {
+            lake.clear_cache();
+        }
+
+        Ok(report)
+    }
+
+    fn check_generation(&self, lake: &DataLake) -> UssResult<()> {
+        if self.generation != lake.generation() {
+            return Err(UssError::StaticError(
+                "ParityFile: the lake was rewritten since the parity was built, rebuild() it",
+            ));
+        }
+
+        Ok(())
+    }
+
+    // solves the bad data units from as many good parity units; false if the
+    // result doesn't match the stored checksums
+    fn reconstruct(&self, group: &mut Group, bad_data: &[usize], good_parity: &[usize]) -> bool {
+        let rows = &good_parity[..bad_data.len()];
+        let parity_units = self.options.parity_units;
+
+        // each parity unit minus the contribution of the good data units
+        let syndromes: Vec<Vec<u8>> = rows
+            .iter()
+            .map(|&i| {
+                let mut syndrome = group.parity[i].clone();
+
+                for (j, unit) in group.data.iter().enumerate() {
+                    if !bad_data.contains(&j) {
+                        gf_mul_add(
+                            &mut syndrome,
+                            unit,
+                            cauchy(parity_units, i as u32, j as u32),
+                        );
+                    }
+                }
+
+                syndrome
+            })
+            .collect();
+
+        let mut matrix: Vec<Vec<u8>> = rows
+            .iter()
+            .map(|&i| {
+                bad_data
+                    .iter()
+                    .map(|&j| cauchy(parity_units, i as u32, j as u32))
+                    .collect()
+            })
+            .collect();
+
+        if !gf_invert(&mut matrix) {
+            return false;
+        }
+
+        for (row, &j) in bad_data.iter().enumerate() {
+            let mut unit = vec![0; UNIT];
+
+            for (col, syndrome) in syndromes.iter().enumerate() {
+                gf_mul_add(&mut unit, syndrome, matrix[row][col]);
+            }
+
+            if crate::hasher::checksum_u32(&unit, UNIT as u32) != group.data_checksums[j] {
+                return false;
+            }
+
+            group.data[j] = unit;
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::tests::noise;
+    use std::io::Write;
+
+    // flips a byte at position past the first copy of needle in path,
+    // without truncating a file that may be mapped
+    fn flip(path: &str, needle: &[u8], position: usize) {
+        let at = std::fs::read(path)
+            .unwrap()
+            .windows(needle.len())
+            .position(|window| window == needle)
+            .unwrap();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .unwrap();
+        let mut byte = [0u8; 1];
+
+        file.seek(SeekFrom::Start((at + position) as u64)).unwrap();
+        file.read_exact(&mut byte).unwrap();
+        file.seek(SeekFrom::Start((at + position) as u64)).unwrap();
+        file.write_all(&[byte[0] ^ 0x5a]).unwrap();
+    }
+
+    fn lake_with_parity(name: &str) -> (String, String, Vec<Vec<u8>>) {
+        let (path, sidecar) = (test_path(name), test_path(&format!("{}.parity", name)));
+        // consecutive slices of one stream, so no item repeats another
+        let lengths: Vec<usize> = (0..10).map(|i| 300 + 97 * i).collect();
+        let stream = noise(lengths.iter().sum(), 0);
+        let items: Vec<Vec<u8>> = lengths
+            .iter()
+            .scan(0, |start, length| {
+                *start += length;
+                Some(stream[*start - length..*start].to_vec())
+            })
+            .collect();
+        let lake = DataLake::create(&path, 1 << 20).unwrap();
+
+        for item in items[..5].iter() {
+            lake.put(item).unwrap();
+        }
+
+        let mut parity = ParityFile::create(&sidecar, &lake, ParityOptions::default()).unwrap();
+
+        for item in items[5..].iter() {
+            lake.put(item).unwrap();
+        }
+
+        assert!(parity.update(&lake).unwrap() > 0);
+
+        (path, sidecar, items)
+    }
+
+    #[test]
+    fn scrub_repairs_bad_units() {
+        let (path, sidecar, items) = lake_with_parity("parity.lake");
+
+        flip(&path, &items[2][..32], 100);
+        flip(&path, &items[7][..32], 600);
+
+        let mut lake = DataLake::load(&path, false).unwrap();
+        let mut parity = ParityFile::open(&sidecar, &lake).unwrap();
+        let report = parity.scrub(&mut lake).unwrap();
+
+        assert_eq!((report.bad_units, report.repaired_units), (2, 2));
+        assert!(report.unrecoverable.is_empty());
+
+        for item in items.iter() {
+            let hash = crate::hasher::hash(item);
+
+            assert_eq!(lake.get(&hash).unwrap().read().unwrap(), *item);
+        }
+    }
+
+    #[test]
+    fn create_keeps_an_existing_sidecar() {
+        let (path, sidecar, _) = lake_with_parity("parity-existing.lake");
+        let lake = DataLake::load(&path, true).unwrap();
+        let before = std::fs::read(&sidecar).unwrap();
+
+        assert!(ParityFile::create(&sidecar, &lake, ParityOptions::default()).is_err());
+        assert_eq!(std::fs::read(&sidecar).unwrap(), before);
+        assert!(ParityFile::open(&sidecar, &lake).is_ok());
+    }
+
+    #[test]
+    fn open_rejects_invalid_headers() {
+        let (path, sidecar, _) = lake_with_parity("parity-header.lake");
+        let lake = DataLake::load(&path, true).unwrap();
+        let mut file = OpenOptions::new().write(true).open(&sidecar).unwrap();
+
+        // data_units
+        file.seek(SeekFrom::Start(8)).unwrap();
+        file.write_all(&0u32.to_le_bytes()).unwrap();
+
+        assert!(ParityFile::open(&sidecar, &lake).is_err());
+    }
+
+    #[test]
+    fn rewrites_need_a_rebuild() {
+        let (path, sidecar, _) = lake_with_parity("parity-rewrite.lake");
+        let mut lake = DataLake::load(&path, false).unwrap();
+        let mut parity = ParityFile::open(&sidecar, &lake).unwrap();
+
+        lake.repair().unwrap();
+
+        assert!(parity.scrub(&mut lake).is_err());
+        assert!(parity.update(&lake).is_err());
+
+        parity.rebuild(&lake).unwrap();
+
+        let mut parity = ParityFile::open(&sidecar, &lake).unwrap();
+
+        assert_eq!(parity.scrub(&mut lake).unwrap().bad_units, 0);
+    }
+
+    #[test]
+    fn update_survives_a_corrupt_data_next() {
+        let (path, sidecar, _) = lake_with_parity("parity-data-next.lake");
+        let lake = DataLake::load(&path, false).unwrap();
+        let mut parity = ParityFile::open(&sidecar, &lake).unwrap();
+
+        lake.header().data_next.store(0, Ordering::Release);
+
+        assert_eq!(parity.update(&lake).unwrap(), 0);
+    }
+}
@@ -0,0 +1,138 @@
+use super::*;
+use std::collections::BTreeMap;
+
+// Named refs map names to chunk hashes, e.g. the roots of stored trees.
+// The whole table is one chunk, written anew by every change, and the
+// header points at it by unit offset, so readers switch to a new table
+// atomically. It has to fit in a chunk like any other data.
+impl DataLake {
+    fn read_refs(&self, offset: u32) -> UssResult<BTreeMap<String, [u8; 50]>> {
+        if offset == 0 {
+            return Ok(BTreeMap::new());
+        }
+
+        if !self.in_data_region(offset) {
+            return Err(UssError::StaticError(
+                "DataLake::refs: ref table is outside of the data region",
+            ));
+        }
+
+        let chunk = self.chunk_at(offset)?;
+
+        chunk.verify()?;
+
+        let table: BTreeMap<String, Vec<u8>> =
+            bitcode::deserialize(&chunk.read()?).map_err(to_error)?;
+
+        table
+            .into_iter()
+            .map(|(name, hash)| match <[u8; 50]>::try_from(hash.as_slice()) {
+                Ok(hash) => Ok((name, hash)),
+                Err(_) => Err(UssError::StaticError(
+                    "DataLake::refs: ref table holds a malformed hash",
+                )),
+            })
+            .collect()
+    }
+
+    // offset of the current ref table, zero if there is none
+    pub(super) fn refs_offset(&self) -> u32 {
+        self.header().refs.load(Ordering::Acquire)
+    }
+
+    pub fn refs(&self) -> UssResult<BTreeMap<String, [u8; 50]>> {
+        self.read_refs(self.refs_offset())
+    }
+
+    pub fn get_ref(&self, name: &str) -> UssResult<Option<[u8; 50]>> {
+        Ok(self.refs()?.get(name).copied())
+    }
+
+    // points name at hash, which doesn't have to be stored (yet)
+    pub fn set_ref(&self, name: &str, hash: &[u8; 50]) -> UssResult<()> {
+        self.update_refs(|refs| {
+            refs.insert(name.to_owned(), *hash);
+        })
+    }
+
+    // returns whether there was a ref of that name
+    pub fn remove_ref(&self, name: &str) -> UssResult<bool> {
+        let mut removed = false;
+
+        self.update_refs(|refs| removed = refs.remove(name).is_some())?;
+
+        Ok(removed)
+    }
+
+    // Applies change to the current table under the writer lock, stores the
+    // result and publishes its offset. Old tables stay in the data region.
+    pub(super) fn update_refs(
+        &self,
+        change: impl FnOnce(&mut BTreeMap<String, [u8; 50]>),
+    ) -> UssResult<()> {
+        if self.readonly {
+            return Err(UssError::StaticError(
+                "Must not change refs of a readonly lake.",
+            ));
+        }
+
+        let _writer = self.lock_appends()?;
+        let mut refs = self.refs()?;
+
+        change(&mut refs);
+
+        let table: BTreeMap<&str, Vec<u8>> = refs
+            .iter()
+            .map(|(name, hash)| (name.as_str(), hash.to_vec()))
+            .collect();
+        let data = bitcode::serialize(&table).map_err(to_error)?;
+        let hash = self.hash_of(&data);
+
+        let offset = match self.probe(&hash) {
+            Some((_, offset)) => offset,
+            None => {
+                let (header, payload) = self.encode_chunk(hash, &data)?;
+
+                self.append_chunk(&mut *self.lock_map()?, header, &payload)?
+                    .offset
+            }
+        };
+
+        self.header().refs.store(offset, Ordering::Release);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refs_survive_reload() {
+        let path = test_path("refs.lake");
+        let root = crate::hasher::hash(b"root");
+        let other = crate::hasher::hash(b"other");
+
+        {
+            let lake = DataLake::create(&path, 1 << 20).unwrap();
+
+            assert!(lake.refs().unwrap().is_empty());
+
+            lake.set_ref("main", &root).unwrap();
+            lake.set_ref("backup", &other).unwrap();
+            lake.set_ref("main", &other).unwrap();
+
+            assert!(lake.remove_ref("backup").unwrap());
+            assert!(!lake.remove_ref("backup").unwrap());
+        }
+
+        let lake = DataLake::load(&path, true).unwrap();
+
+        assert_eq!(lake.get_ref("main").unwrap(), Some(other));
+        assert_eq!(lake.get_ref("backup").unwrap(), None);
+        assert_eq!(lake.refs().unwrap().len(), 1);
+        assert!(lake.set_ref("main", &root).is_err());
+        assert!(lake.fsck().unwrap().is_clean());
+    }
+}
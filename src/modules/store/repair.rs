@@ -0,0 +1,201 @@
+use super::*;
+use serde::Serialize;
+use std::collections::HashSet;
+
+#[derive(Debug, Default, Serialize)]
+pub struct RepairReport {
+    // header fields that were reset to the layout implied by the file size
+    pub header_fixes: Vec<String>,
+    pub chunks_indexed: usize,
+    // later copies of an already indexed hash, left unindexed
+    pub duplicates: usize,
+    // chunks whose data does not match their hash, left unindexed
+    pub corrupt: usize,
+    // valid chunks that no longer fit in the index, left unindexed
+    pub dropped: usize,
+    // non-empty units that did not start a valid chunk
+    pub skipped_units: u32,
+    pub data_next_before: u32,
+    pub data_next_after: u32,
+}
+
+impl DataLake {
+    fn repair_header(&mut self, report: &mut RepairReport) {
+        let format = IndexFormat::from_header(self.header().index_format);
+
+        // an unknown format can't be told apart from a corrupt linear one
+        let expected = DataLakeHeader::for_file_size(
+            self.data.roref.len() as u64,
+            format.unwrap_or(IndexFormat::Linear),
+        );
+
+        let header = self.header_mut();
+
+        if format.is_none() {
+            report
+                .header_fixes
+                .push(format!("index_format: {} -> 0", header.index_format));
+            header.index_format = expected.index_format;
+        }
+
+        if header.magic != expected.magic {
+            report.header_fixes.push("magic".to_owned());
+            header.magic = expected.magic;
+        }
+
+        if header.file_size != expected.file_size {
+            report.header_fixes.push(format!(
+                "file_size: {} -> {}",
+                header.file_size, expected.file_size
+            ));
+            header.file_size = expected.file_size;
+        }
+
+        let fields: [(&str, &mut u32, u32); 6] = [
+            ("data_size", &mut header.data_size, expected.data_size),
+            ("data_offset", &mut header.data_offset, expected.data_offset),
+            ("index_mod", &mut header.index_mod, expected.index_mod),
+            ("index_max", &mut header.index_max, expected.index_max),
+            (
+                "index_offset",
+                &mut header.index_offset,
+                expected.index_offset,
+            ),
+            (
+                "index_offset_u32",
+                &mut header.index_offset_u32,
+                expected.index_offset_u32,
+            ),
+        ];
+
+        for (name, value, expected) in fields {
+            if *value != expected {
+                report
+                    .header_fixes
+                    .push(format!("{}: {} -> {}", name, value, expected));
+                *value = expected;
+            }
+        }
+    }
+
+    // Clears the index and rebuilds it from a scan of the data region.
+    // data_next is recovered from the last valid chunk, so the scan runs to
+    // the end of the file rather than trusting the header. Chunks that don't
+    // fit in the index are counted as dropped instead of failing halfway.
+    pub fn repair(&mut self) -> UssResult<RepairReport> {
+        if self.readonly {
+            return Err(UssError::StaticError(
+                "Must not call repair() on readonly lake.",
+            ));
+        }
+
+        let _rewrite = self.lock_rewrite()?;
+
+        // data_next may move back over units that get written again
+        self.bump_generation();
+
+        let mut report = RepairReport {
+            data_next_before: self.data_next(),
+            ..Default::default()
+        };
+
+        self.repair_header(&mut report);
+
+        let mut map = match &self.data.owned_rw {
+            Some(arc) => arc.lock().map_err(to_error)?,
+            None => return Err(UssError::StaticError("repair() called on read-only map")),
+        };
+
+        let index_start = (self.header().index_offset_u32 as usize) << 2;
+        let index_end = offset_to_data_offset(self.header().data_offset);
+
+        map[index_start..index_end].fill(0);
+        self.header().index_used.store(0, Ordering::Release);
+
+        // payloads of encrypted lakes can only be checked with the key
+        let check_payloads = !self.encrypted || self.key.is_some();
+        let end = self.mapped_units();
+        let mut seen: HashSet<[u8; 50]> = HashSet::new();
+        let mut data_next = self.header().data_offset;
+        let mut offset = self.header().data_offset;
+
+        while offset < end {
+            let header = read_header(&self.data, offset);
+
+            if header.hash[0] == 0 {
+                offset += 1;
+                continue;
+            }
+
+            let units = header.units();
+
+            if !crate::hasher::verify_hash_integrity(&header.hash) || offset + units > end {
+                report.skipped_units += 1;
+                offset += 1;
+                continue;
+            }
+
+            data_next = offset + units;
+
+            if check_payloads && self.chunk_at(offset)?.verify().is_err() {
+                report.corrupt += 1;
+            } else if !seen.insert(header.hash) {
+                report.duplicates += 1;
+            } else if self.index_insert(&mut map, &header.hash, offset).is_err() {
+                // keep scanning, so data_next still covers every chunk
+                report.dropped += 1;
+            } else {
+                report.chunks_indexed += 1;
+            }
+
+            offset += units;
+        }
+
+        drop(map);
+
+        self.header().data_next.store(data_next, Ordering::Release);
+        self.clear_cache();
+
+        if self.filter.is_some() {
+            self.reset_filter()?;
+        }
+
+        report.data_next_after = data_next;
+
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn full_index_drops_chunks_instead_of_failing() {
+        let path = test_path("repair-full-index.lake");
+        let mut lake = DataLake::create(&path, 1 << 16).unwrap();
+        let mut stored = Vec::new();
+
+        // the failed put still leaves its chunk in the data region
+        for i in 0.. {
+            match lake.put(format!("chunk number {}", i).as_bytes()) {
+                Ok(chunk) => stored.push((i, chunk.header.hash)),
+                Err(_) => break,
+            }
+        }
+
+        let data_next = lake.data_next();
+        let report = lake.repair().unwrap();
+
+        assert_eq!(report.dropped, 1);
+        assert_eq!(report.chunks_indexed, stored.len());
+        assert_eq!(report.data_next_after, data_next);
+
+        for (i, hash) in &stored {
+            assert_eq!(
+                lake.get(hash).unwrap().read().unwrap(),
+                format!("chunk number {}", i).as_bytes()
+            );
+        }
+    }
+}
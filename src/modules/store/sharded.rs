@@ -0,0 +1,480 @@
+use super::*;
+use std::path::{Path, PathBuf};
+
+pub const MANIFEST_NAME: &str = "manifest";
+const MANIFEST_MAGIC: &str = "uss-sharded";
+const MANIFEST_VERSION: u32 = 1;
+
+// Layout of a sharded lake directory, stored as "key value" lines in
+// <dir>/manifest. Shards are <dir>/shard-NNNN.lake.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ShardManifest {
+    pub shards: u32,
+    // file size each shard was created with
+    pub shard_size: u64,
+    // hashes new chunks are routed and stored by, Legacy in manifests
+    // from before it was recorded
+    pub hash_algorithm: HashAlgorithm,
+}
+
+impl ShardManifest {
+    pub fn read(dir: &Path) -> UssResult<ShardManifest> {
+        let text = std::fs::read_to_string(dir.join(MANIFEST_NAME)).map_err(to_error)?;
+        let mut lines = text.lines();
+
+        match lines.next() {
+            Some(line) if line == format!("{} {}", MANIFEST_MAGIC, MANIFEST_VERSION) => {}
+            _ => {
+                return Err(UssError::StaticError(
+                    "ShardManifest: unknown manifest version",
+                ))
+            }
+        }
+
+        let (mut shards, mut shard_size) = (None, None);
+        let mut hash_algorithm = HashAlgorithm::Legacy;
+
+        for line in lines {
+            let (key, value) = match line.split_once(' ') {
+                Some(pair) => pair,
+                None => continue,
+            };
+
+            match key {
+                "shards" => shards = value.parse::<u32>().ok(),
+                "shard_size" => shard_size = value.parse::<u64>().ok(),
+                "routing" if value != "base64-prefix" => {
+                    return Err(UssError::DynamicError(format!(
+                        "ShardManifest: unknown routing {}",
+                        value
+                    )))
+                }
+                "hash_algorithm" => match HashAlgorithm::from_name(value) {
+                    Some(algorithm) => hash_algorithm = algorithm,
+                    None => {
+                        return Err(UssError::DynamicError(format!(
+                            "ShardManifest: unknown hash algorithm {}",
+                            value
+                        )))
+                    }
+                },
+                _ => {}
+            }
+        }
+
+        match (shards, shard_size) {
+            (Some(shards), Some(shard_size)) if shards > 0 => Ok(ShardManifest {
+                shards,
+                shard_size,
+                hash_algorithm,
+            }),
+            _ => Err(UssError::StaticError(
+                "ShardManifest: missing or invalid layout",
+            )),
+        }
+    }
+
+    // written under a temporary name, so a layout without a complete
+    // manifest can't be opened
+    pub fn write(&self, dir: &Path) -> UssResult<()> {
+        let temp = dir.join(format!(".{}.tmp", MANIFEST_NAME));
+        let text = format!(
+            "{} {}\nshards {}\nshard_size {}\nrouting base64-prefix\nhash_algorithm {}\n",
+            MANIFEST_MAGIC,
+            MANIFEST_VERSION,
+            self.shards,
+            self.shard_size,
+            self.hash_algorithm.name()
+        );
+
+        std::fs::write(&temp, text).map_err(to_error)?;
+        std::fs::rename(&temp, dir.join(MANIFEST_NAME)).map_err(to_error)
+    }
+
+    pub fn shard_path(&self, dir: &Path, shard: u32) -> PathBuf {
+        dir.join(format!("shard-{:04}.lake", shard))
+    }
+
+    // Routes by the first 24 bits of the hash, decoded from its first four
+    // base64 characters. Those come straight from the digest, and unlike
+    // get_index_offset() they don't correlate with the slot inside a shard.
+    pub fn shard_of(&self, hash: &[u8; 50]) -> u32 {
+        let prefix = crate::base64::decode(&hash[..4]);
+        let value = ((prefix[0] as u32) << 16) | ((prefix[1] as u32) << 8) | prefix[2] as u32;
+
+        value % self.shards
+    }
+}
+
+// N DataLake files in one directory, each chunk stored in the shard its
+// hash routes to. Shards have their own writer lock, so puts that land in
+// different shards don't wait on each other.
+pub struct ShardedLake {
+    dir: PathBuf,
+    manifest: ShardManifest,
+    shards: Vec<DataLake>,
+}
+
+impl ShardedLake {
+    pub fn create<P: AsRef<Path>>(
+        dir: P,
+        shards: u32,
+        shard_size: u64,
+        options: DataLakeOptions,
+    ) -> UssResult<ShardedLake> {
+        let lake = ShardedLake::create_shards(dir.as_ref(), shards, shard_size, options)?;
+
+        lake.manifest.write(&lake.dir)?;
+
+        Ok(lake)
+    }
+
+    // shards without a manifest; the caller writes it once they're complete
+    fn create_shards(
+        dir: &Path,
+        shards: u32,
+        shard_size: u64,
+        options: DataLakeOptions,
+    ) -> UssResult<ShardedLake> {
+        if shards == 0 {
+            return Err(UssError::StaticError(
+                "ShardedLake: need at least one shard",
+            ));
+        }
+
+        if dir.join(MANIFEST_NAME).exists() {
+            return Err(UssError::DynamicError(format!(
+                "{} already holds a sharded lake",
+                dir.display()
+            )));
+        }
+
+        let manifest = ShardManifest {
+            shards,
+            shard_size,
+            hash_algorithm: options.hash_algorithm.unwrap_or_default(),
+        };
+
+        // leftovers of an interrupted create or reshard aren't ours to remove
+        for shard in 0..shards {
+            let path = manifest.shard_path(dir, shard);
+
+            if std::fs::metadata(&path).is_ok() {
+                return Err(UssError::DynamicError(format!(
+                    "File {} already exists",
+                    path.display()
+                )));
+            }
+        }
+
+        std::fs::create_dir_all(dir).map_err(to_error)?;
+
+        let mut lakes = Vec::with_capacity(shards as usize);
+
+        for shard in 0..shards {
+            let path = manifest.shard_path(dir, shard);
+
+            lakes.push(DataLake::create_with_options(
+                &path.to_string_lossy(),
+                shard_size,
+                options.clone(),
+            )?);
+        }
+
+        Ok(ShardedLake {
+            dir: dir.to_path_buf(),
+            manifest,
+            shards: lakes,
+        })
+    }
+
+    // options.hash_algorithm is taken from the manifest, and every shard
+    // has to have been created with it
+    pub fn open<P: AsRef<Path>>(
+        dir: P,
+        readonly: bool,
+        options: DataLakeOptions,
+    ) -> UssResult<ShardedLake> {
+        let dir = dir.as_ref();
+        let manifest = ShardManifest::read(dir)?;
+
+        if options
+            .hash_algorithm
+            .is_some_and(|given| given != manifest.hash_algorithm)
+        {
+            return Err(UssError::StaticError(
+                "ShardedLake: manifest names a different hash algorithm",
+            ));
+        }
+
+        let options = DataLakeOptions {
+            hash_algorithm: Some(manifest.hash_algorithm),
+            ..options
+        };
+        let mut lakes = Vec::with_capacity(manifest.shards as usize);
+
+        for shard in 0..manifest.shards {
+            lakes.push(DataLake::load_with_options(
+                &manifest.shard_path(dir, shard).to_string_lossy(),
+                readonly,
+                options.clone(),
+            )?);
+        }
+
+        Ok(ShardedLake {
+            dir: dir.to_path_buf(),
+            manifest,
+            shards: lakes,
+        })
+    }
+
+    pub fn manifest(&self) -> &ShardManifest {
+        &self.manifest
+    }
+
+    pub fn shards(&self) -> &[DataLake] {
+        &self.shards
+    }
+
+    pub fn shard(&self, hash: &[u8; 50]) -> &DataLake {
+        &self.shards[self.manifest.shard_of(hash) as usize]
+    }
+
+    pub fn get(&self, hash: &[u8; 50]) -> Option<DataChunk> {
+        self.shard(hash).get(hash)
+    }
+
+    // shards hash with the manifest's algorithm too, so the chunk is
+    // stored under the hash it was routed by
+    pub fn put(&self, data: &[u8]) -> UssResult<DataChunk> {
+        self.shard(&self.hash_of(data)).put(data)
+    }
+
+    pub fn hash_of(&self, data: &[u8]) -> [u8; 50] {
+        hash_with(self.manifest.hash_algorithm, data)
+    }
+
+    // groups items by shard and fills the shards in parallel
+    pub fn put_many(&self, items: &[&[u8]]) -> UssResult<Vec<DataChunk>> {
+        let mut groups: Vec<Vec<usize>> = vec![Vec::new(); self.shards.len()];
+
+        for (item, data) in items.iter().enumerate() {
+            groups[self.manifest.shard_of(&self.hash_of(data)) as usize].push(item);
+        }
+
+        let results = std::thread::scope(|scope| {
+            let handles: Vec<_> = groups
+                .iter()
+                .enumerate()
+                .filter(|(_, group)| !group.is_empty())
+                .map(|(shard, group)| {
+                    let lake = &self.shards[shard];
+                    let data: Vec<&[u8]> = group.iter().map(|&item| items[item]).collect();
+
+                    scope.spawn(move || lake.put_many(&data))
+                })
+                .collect();
+
+            handles
+                .into_iter()
+                .map(|handle| {
+                    handle.join().unwrap_or(Err(UssError::StaticError(
+                        "ShardedLake::put_many: a shard's writer thread panicked",
+                    )))
+                })
+                .collect::<Vec<_>>()
+        });
+
+        let mut chunks: Vec<Option<DataChunk>> = vec![None; items.len()];
+        let written = groups.iter().filter(|group| !group.is_empty());
+
+        for (group, result) in written.zip(results) {
+            for (&item, chunk) in group.iter().zip(result?) {
+                chunks[item] = Some(chunk);
+            }
+        }
+
+        Ok(chunks.into_iter().flatten().collect())
+    }
+
+    // chunks of every shard, shard by shard
+    pub fn iter(&self) -> impl Iterator<Item = DataChunk> + '_ {
+        self.shards.iter().flat_map(|shard| shard.iter())
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(|shard| shard.is_empty())
+    }
+
+    // Streams every chunk into a new layout in another directory. The
+    // manifest is written last, so an interrupted reshard leaves nothing
+    // that open() accepts; this lake is left untouched. Without
+    // options.hash_algorithm the new layout keeps this one's.
+    pub fn reshard<P: AsRef<Path>>(
+        &self,
+        dir: P,
+        shards: u32,
+        shard_size: u64,
+        options: DataLakeOptions,
+    ) -> UssResult<ShardedLake> {
+        if dir.as_ref() == self.dir {
+            return Err(UssError::StaticError(
+                "ShardedLake: reshard into a different directory",
+            ));
+        }
+
+        let options = DataLakeOptions {
+            hash_algorithm: options
+                .hash_algorithm
+                .or(Some(self.manifest.hash_algorithm)),
+            ..options
+        };
+        let target = ShardedLake::create_shards(dir.as_ref(), shards, shard_size, options)?;
+
+        for chunk in self.iter() {
+            let hash = chunk.header.hash;
+
+            target.shard(&hash).put_hashed(&hash, &chunk.read()?)?;
+        }
+
+        target.manifest.write(&target.dir)?;
+
+        Ok(target)
+    }
+}
+
+impl ChunkStore for ShardedLake {
+    fn get(&self, hash: &[u8; 50]) -> UssResult<Option<Vec<u8>>> {
+        ChunkStore::get(self.shard(hash), hash)
+    }
+
+    fn put(&self, data: &[u8]) -> UssResult<[u8; 50]> {
+        Ok(ShardedLake::put(self, data)?.header.hash)
+    }
+
+    fn has(&self, hash: &[u8; 50]) -> bool {
+        self.get(hash).is_some()
+    }
+
+    fn get_with<'a>(
+        &'a self,
+        hash: &[u8; 50],
+        buffer: &'a mut Vec<u8>,
+    ) -> UssResult<Option<&'a [u8]>> {
+        DataLake::get_with(self.shard(hash), hash, buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let dir = test_path("sharded");
+        let items: Vec<Vec<u8>> = (0..100)
+            .map(|i| format!("sharded chunk {}", i).into_bytes())
+            .collect();
+        let refs: Vec<&[u8]> = items.iter().map(|item| item.as_slice()).collect();
+
+        {
+            let lake = ShardedLake::create(&dir, 4, 1 << 20, DataLakeOptions::default()).unwrap();
+            let chunks = lake.put_many(&refs).unwrap();
+
+            assert_eq!(chunks.len(), items.len());
+            assert_eq!(
+                lake.put(&items[0]).unwrap().header.hash,
+                chunks[0].header.hash
+            );
+        }
+
+        let lake = ShardedLake::open(&dir, true, DataLakeOptions::default()).unwrap();
+
+        assert_eq!(lake.manifest().shards, 4);
+        assert_eq!(lake.len(), items.len());
+        assert!(lake.shards().iter().all(|shard| !shard.is_empty()));
+
+        for item in items.iter() {
+            let hash = crate::hasher::hash(item);
+
+            assert_eq!(lake.get(&hash).unwrap().read().unwrap(), *item);
+        }
+    }
+
+    #[test]
+    fn create_keeps_existing_shards() {
+        let dir = PathBuf::from(test_path("sharded-leftover"));
+        let manifest = ShardManifest {
+            shards: 2,
+            shard_size: 1 << 20,
+            hash_algorithm: HashAlgorithm::Legacy,
+        };
+
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(manifest.shard_path(&dir, 1), b"not ours").unwrap();
+
+        assert!(ShardedLake::create(&dir, 2, 1 << 20, DataLakeOptions::default()).is_err());
+        assert!(std::fs::metadata(manifest.shard_path(&dir, 0)).is_err());
+        assert_eq!(
+            std::fs::read(manifest.shard_path(&dir, 1)).unwrap(),
+            b"not ours"
+        );
+    }
+
+    #[test]
+    fn routes_by_the_manifest_algorithm() {
+        let dir = test_path("sharded-blake3");
+        let options = DataLakeOptions {
+            hash_algorithm: Some(HashAlgorithm::Blake3),
+            ..Default::default()
+        };
+        let items: Vec<Vec<u8>> = (0..50)
+            .map(|i| format!("tagged chunk {}", i).into_bytes())
+            .collect();
+        let refs: Vec<&[u8]> = items.iter().map(|item| item.as_slice()).collect();
+
+        ShardedLake::create(&dir, 4, 1 << 20, options)
+            .unwrap()
+            .put_many(&refs[..25])
+            .unwrap();
+
+        // opened without saying which algorithm the layout uses
+        let lake = ShardedLake::open(&dir, false, DataLakeOptions::default()).unwrap();
+
+        assert_eq!(lake.manifest().hash_algorithm, HashAlgorithm::Blake3);
+
+        for item in items[25..].iter() {
+            lake.put(item).unwrap();
+        }
+
+        for item in items.iter() {
+            let hash = hash_with(HashAlgorithm::Blake3, item);
+
+            assert_eq!(lake.get(&hash).unwrap().read().unwrap(), *item);
+        }
+
+        assert_eq!(lake.len(), items.len());
+    }
+
+    #[test]
+    fn open_rejects_a_different_algorithm() {
+        let dir = test_path("sharded-algorithm-mismatch");
+        let options = DataLakeOptions {
+            hash_algorithm: Some(HashAlgorithm::Blake3),
+            ..Default::default()
+        };
+
+        ShardedLake::create(&dir, 2, 1 << 20, options).unwrap();
+
+        let legacy = DataLakeOptions {
+            hash_algorithm: Some(HashAlgorithm::Legacy),
+            ..Default::default()
+        };
+
+        assert!(ShardedLake::open(&dir, true, legacy).is_err());
+    }
+}
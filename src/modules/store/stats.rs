@@ -0,0 +1,143 @@
+use super::*;
+use index::IndexFormat;
+use serde::Serialize;
+
+// chunks up to 4096 bytes, in power-of-two size classes
+const HISTOGRAM_BUCKETS: usize = 13;
+
+#[derive(Debug, Serialize)]
+pub struct SizeBucket {
+    // uncompressed sizes up to and including this
+    pub up_to: usize,
+    pub chunks: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LakeStats {
+    pub file_size: u64,
+    // data region, in 256-byte units
+    pub data_units: u32,
+    pub used_units: u32,
+    pub free_units: u32,
+    pub index_format: IndexFormat,
+    pub index_mod: u32,
+    pub index_capacity: u32,
+    pub index_used: usize,
+    pub load_factor: f64,
+    // index entries looked at to find each stored chunk
+    pub average_probe_length: f64,
+    pub max_probe_length: u32,
+    pub chunks: usize,
+    pub uncompressed_bytes: u64,
+    // stored payload bytes, without chunk headers
+    pub compressed_bytes: u64,
+    pub compression_ratio: f64,
+    pub size_histogram: Vec<SizeBucket>,
+    pub cache: CacheStats,
+}
+
+impl DataLake {
+    // Walks the data region and the index, so it reads every chunk header.
+    pub fn stats(&self) -> UssResult<LakeStats> {
+        let header = self.header();
+        let data_next = self.data_next();
+        let data_end = header.data_offset + header.data_size;
+
+        let mut stats = LakeStats {
+            file_size: header.file_size,
+            data_units: header.data_size,
+            used_units: data_next.saturating_sub(header.data_offset),
+            free_units: data_end.saturating_sub(data_next),
+            index_format: self.index_format(),
+            index_mod: header.index_mod,
+            index_capacity: self.index_capacity(),
+            index_used: 0,
+            load_factor: 0.0,
+            average_probe_length: 0.0,
+            max_probe_length: 0,
+            chunks: 0,
+            uncompressed_bytes: 0,
+            compressed_bytes: 0,
+            compression_ratio: 0.0,
+            size_histogram: (0..HISTOGRAM_BUCKETS)
+                .map(|bucket| SizeBucket {
+                    up_to: 1 << bucket,
+                    chunks: 0,
+                })
+                .collect(),
+            cache: self.cache_stats(),
+        };
+
+        for offset in self.chunk_offsets()? {
+            let header = read_header(&self.data, offset);
+            let size = header.uncompressed_length as usize;
+
+            stats.chunks += 1;
+            stats.uncompressed_bytes += size as u64;
+            stats.compressed_bytes += header.compressed_length as u64;
+
+            let bucket = size.next_power_of_two().trailing_zeros() as usize;
+
+            stats.size_histogram[std::cmp::min(bucket, HISTOGRAM_BUCKETS - 1)].chunks += 1;
+        }
+
+        let mut probe_total: u64 = 0;
+
+        for (_, offset) in self.index_entries() {
+            stats.index_used += 1;
+
+            if !self.in_data_region(offset) {
+                continue;
+            }
+
+            let probe = self.probe_with_stats(&read_header(&self.data, offset).hash);
+
+            probe_total += probe.entries as u64;
+            stats.max_probe_length = std::cmp::max(stats.max_probe_length, probe.entries);
+        }
+
+        if stats.index_used > 0 {
+            stats.average_probe_length = probe_total as f64 / stats.index_used as f64;
+        }
+
+        if stats.index_capacity > 0 {
+            stats.load_factor = stats.index_used as f64 / stats.index_capacity as f64;
+        }
+
+        if stats.compressed_bytes > 0 {
+            stats.compression_ratio =
+                stats.uncompressed_bytes as f64 / stats.compressed_bytes as f64;
+        }
+
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_stored_chunks() {
+        let lake = DataLake::create(&test_path("stats.lake"), 1 << 20).unwrap();
+
+        lake.put(b"first chunk").unwrap();
+        lake.put(b"second chunk").unwrap();
+
+        let stats = lake.stats().unwrap();
+
+        assert_eq!(stats.chunks, 2);
+        assert_eq!(stats.index_used, 2);
+        assert_eq!(stats.used_units, 2);
+        assert_eq!(stats.used_units + stats.free_units, stats.data_units);
+    }
+
+    #[test]
+    fn corrupt_data_next_does_not_underflow() {
+        let lake = DataLake::create(&test_path("stats-corrupt.lake"), 1 << 20).unwrap();
+
+        lake.header().data_next.store(0, Ordering::Release);
+
+        assert_eq!(lake.stats().unwrap().used_units, 0);
+    }
+}
@@ -0,0 +1,377 @@
+use super::*;
+use serde::Serialize;
+use std::{
+    collections::HashSet,
+    sync::{atomic::AtomicBool, RwLock, Weak},
+    thread::JoinHandle,
+    time::Duration,
+};
+
+#[derive(Debug, Default, Serialize)]
+pub struct MigrationReport {
+    // chunks copied to the cold tier and dropped from the hot lake
+    pub moved: usize,
+    // chunks already in a cold lake, dropped from the hot lake
+    pub dropped: usize,
+    pub kept: usize,
+}
+
+// A small hot lake in front of cold lakes. Writes go to the hot lake;
+// migrate() moves chunks that weren't read or written since the previous
+// migration to the first cold lake, and rebuilds the hot lake without them.
+pub struct TieredStore {
+    hot: RwLock<Arc<DataLake>>,
+    hot_path: String,
+    hot_size: u64,
+    options: DataLakeOptions,
+    cold: Vec<Arc<DataLake>>,
+    // hot chunks accessed since the last migration
+    recent: Mutex<HashSet<[u8; 50]>>,
+    // serializes migrations
+    migrating: Mutex<()>,
+}
+
+impl TieredStore {
+    // creates the hot lake if it doesn't exist
+    pub fn open(
+        hot_path: &str,
+        hot_size: u64,
+        options: DataLakeOptions,
+        cold: Vec<Arc<DataLake>>,
+    ) -> UssResult<TieredStore> {
+        let hot = match std::fs::metadata(hot_path) {
+            Ok(_) => DataLake::load_with_options(hot_path, false, options.clone())?,
+            Err(_) => DataLake::create_with_options(hot_path, hot_size, options.clone())?,
+        };
+
+        Ok(TieredStore {
+            hot: RwLock::new(Arc::new(hot)),
+            hot_path: hot_path.to_owned(),
+            hot_size,
+            options,
+            cold,
+            recent: Mutex::new(HashSet::new()),
+            migrating: Mutex::new(()),
+        })
+    }
+
+    pub fn hot(&self) -> UssResult<Arc<DataLake>> {
+        match self.hot.read() {
+            Ok(hot) => Ok(hot.clone()),
+            Err(_) => Err(UssError::MutexPoison),
+        }
+    }
+
+    fn touch(&self, hash: &[u8; 50]) {
+        if let Ok(mut recent) = self.recent.lock() {
+            recent.insert(*hash);
+        }
+    }
+
+    // tiers in lookup order
+    fn tiers(&self) -> UssResult<Vec<Arc<DataLake>>> {
+        let mut tiers = vec![self.hot()?];
+
+        tiers.extend(self.cold.iter().cloned());
+
+        Ok(tiers)
+    }
+
+    pub fn migrate(&self) -> UssResult<MigrationReport> {
+        let _migrating = self.migrating.lock().map_err(|_| UssError::MutexPoison)?;
+        let mut report = MigrationReport::default();
+
+        let target = match self.cold.first() {
+            Some(target) => target,
+            None => return Ok(report),
+        };
+
+        let recent = match self.recent.lock() {
+            Ok(mut recent) => std::mem::take(&mut *recent),
+            Err(_) => return Err(UssError::MutexPoison),
+        };
+
+        // copy cold chunks while the hot lake stays readable and writable
+        let mut moving: HashSet<[u8; 50]> = HashSet::new();
+        let hot = self.hot()?;
+
+        for chunk in hot.iter() {
+            let hash = chunk.header.hash;
+
+            if recent.contains(&hash) {
+                continue;
+            }
+
+            if self.cold.iter().any(|cold| cold.has(&hash)) {
+                report.dropped += 1;
+            } else {
+                target.put_hashed(&hash, &chunk.read()?)?;
+                report.moved += 1;
+            }
+
+            moving.insert(hash);
+        }
+
+        if moving.is_empty() {
+            report.kept = hot.len();
+            return Ok(report);
+        }
+
+        // rebuild the hot lake from the rest, still without blocking puts
+        let rebuilt_path = format!("{}.migrating", self.hot_path);
+
+        let _ = std::fs::remove_file(&rebuilt_path);
+
+        let rebuilt =
+            DataLake::create_with_options(&rebuilt_path, self.hot_size, self.options.clone())?;
+        let built_to = hot.data_next();
+
+        for chunk in hot.iter() {
+            if moving.contains(&chunk.header.hash) {
+                continue;
+            }
+
+            rebuilt.put_hashed(&chunk.header.hash, &chunk.read()?)?;
+            report.kept += 1;
+        }
+
+        // put() holds the read side while it writes, so once the write side
+        // is taken no chunk lands in the old lake; only those stored since
+        // the rebuild started are left to copy
+        let mut guard = self.hot.write().map_err(|_| UssError::MutexPoison)?;
+
+        for chunk in guard.iter_from(built_to) {
+            if moving.contains(&chunk.header.hash) || rebuilt.has(&chunk.header.hash) {
+                continue;
+            }
+
+            rebuilt.put_hashed(&chunk.header.hash, &chunk.read()?)?;
+            report.kept += 1;
+        }
+
+        // the rebuilt lake keeps its flocks across the renames; readers that
+        // still hold the old lake keep their mapping of the unlinked file
+        std::fs::rename(
+            writer_lock_path(&rebuilt_path),
+            writer_lock_path(&self.hot_path),
+        )
+        .map_err(to_error)?;
+        std::fs::rename(&rebuilt_path, &self.hot_path).map_err(to_error)?;
+        *guard = Arc::new(rebuilt);
+
+        Ok(report)
+    }
+
+    // Runs migrate() every interval until the handle is stopped or dropped,
+    // or the store itself is dropped. Failed migrations are retried at the
+    // next interval; the handle keeps the latest error.
+    pub fn spawn_migration(self: &Arc<Self>, interval: Duration) -> MigrationHandle {
+        let store: Weak<TieredStore> = Arc::downgrade(self);
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let last_error = Arc::new(Mutex::new(None));
+        let failed = last_error.clone();
+
+        let thread = std::thread::spawn(move || {
+            let step = std::cmp::min(interval, Duration::from_millis(100));
+            let mut waited = Duration::ZERO;
+
+            while !stopped.load(Ordering::Acquire) {
+                std::thread::sleep(step);
+                waited += step;
+
+                if waited < interval {
+                    continue;
+                }
+
+                waited = Duration::ZERO;
+
+                match store.upgrade() {
+                    Some(store) => {
+                        if let Err(err) = store.migrate() {
+                            if let Ok(mut failed) = failed.lock() {
+                                *failed = Some(err);
+                            }
+                        }
+                    }
+                    None => return,
+                }
+            }
+        });
+
+        MigrationHandle {
+            stop,
+            thread: Some(thread),
+            last_error,
+        }
+    }
+}
+
+pub struct MigrationHandle {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+    last_error: Arc<Mutex<Option<UssError>>>,
+}
+
+impl MigrationHandle {
+    // the error of the latest failed migration not yet taken
+    pub fn take_error(&self) -> Option<UssError> {
+        self.last_error.lock().ok()?.take()
+    }
+
+    // waits for a running migration, and returns its error like take_error()
+    pub fn stop(mut self) -> Option<UssError> {
+        self.shutdown();
+        self.take_error()
+    }
+
+    fn shutdown(&mut self) {
+        self.stop.store(true, Ordering::Release);
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for MigrationHandle {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+impl ChunkStore for TieredStore {
+    // A copy that doesn't match its hash is skipped in favour of the next tier.
+    fn get(&self, hash: &[u8; 50]) -> UssResult<Option<Vec<u8>>> {
+        let mut corrupt = false;
+
+        for (tier, lake) in self.tiers()?.iter().enumerate() {
+            let data = match ChunkStore::get(lake.as_ref(), hash) {
+                Ok(Some(data)) => data,
+                Ok(None) => continue,
+                Err(_) => {
+                    corrupt = true;
+                    continue;
+                }
+            };
+
+            if !crate::hasher::verify(hash, &data) {
+                corrupt = true;
+                continue;
+            }
+
+            if tier == 0 {
+                self.touch(hash);
+            }
+
+            return Ok(Some(data));
+        }
+
+        match corrupt {
+            true => Err(UssError::Corruption(*hash)),
+            false => Ok(None),
+        }
+    }
+
+    // Holds the read side of the hot lake until the chunk is written, so
+    // migrate() can't rebuild the hot lake without it.
+    fn put(&self, data: &[u8]) -> UssResult<[u8; 50]> {
+        let hot = self.hot.read().map_err(|_| UssError::MutexPoison)?;
+        let hash = hot.hash_of(data);
+
+        if self.cold.iter().any(|cold| cold.has(&hash)) {
+            return Ok(hash);
+        }
+
+        hot.put(data)?;
+        self.touch(&hash);
+
+        Ok(hash)
+    }
+
+    fn has(&self, hash: &[u8; 50]) -> bool {
+        match self.tiers() {
+            Ok(tiers) => tiers.iter().any(|lake| lake.has(hash)),
+            Err(_) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn puts_survive_concurrent_migrations() {
+        let cold = Arc::new(DataLake::create(&test_path("tiered-cold.lake"), 1 << 24).unwrap());
+        let store = Arc::new(
+            TieredStore::open(
+                &test_path("tiered-hot.lake"),
+                1 << 24,
+                DataLakeOptions::default(),
+                vec![cold],
+            )
+            .unwrap(),
+        );
+
+        let writers: Vec<_> = (0..4)
+            .map(|thread| {
+                let store = store.clone();
+
+                std::thread::spawn(move || {
+                    (0..1000)
+                        .map(|i| {
+                            let data = format!("thread {} chunk {}", thread, i).into_bytes();
+
+                            (store.put(&data).unwrap(), data)
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+
+        while !writers.iter().all(|writer| writer.is_finished()) {
+            store.migrate().unwrap();
+        }
+
+        store.migrate().unwrap();
+
+        for writer in writers {
+            for (hash, data) in writer.join().unwrap() {
+                assert_eq!(store.get(&hash).unwrap().unwrap(), data);
+            }
+        }
+    }
+
+    #[test]
+    fn migration_handle_keeps_the_last_error() {
+        let cold_path = test_path("tiered-readonly-cold.lake");
+
+        drop(DataLake::create(&cold_path, 1 << 20).unwrap());
+
+        // chunks can't be moved to a readonly cold lake
+        let cold = Arc::new(DataLake::load(&cold_path, true).unwrap());
+        let store = Arc::new(
+            TieredStore::open(
+                &test_path("tiered-failing-hot.lake"),
+                1 << 20,
+                DataLakeOptions::default(),
+                vec![cold],
+            )
+            .unwrap(),
+        );
+
+        store.put(b"never touched again").unwrap();
+        store.migrate().unwrap();
+
+        let handle = store.spawn_migration(Duration::from_millis(10));
+        let started = std::time::Instant::now();
+
+        while handle.last_error.lock().unwrap().is_none() {
+            assert!(started.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        assert!(matches!(handle.stop(), Some(UssError::StaticError(_))));
+    }
+}
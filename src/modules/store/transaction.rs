@@ -0,0 +1,381 @@
+use super::*;
+use std::{collections::HashMap, sync::MutexGuard};
+
+// Chunks put in a transaction are written past data_next, where neither
+// readers nor other processes look, and become visible together at commit().
+// The transaction holds the writer lock until it is committed or dropped;
+// DataLake::put() on the same thread meanwhile fails rather than deadlock.
+pub struct Transaction<'a> {
+    lake: &'a DataLake,
+    _writer: MutexGuard<'a, ()>,
+    // data_next when the transaction began
+    start: u32,
+    next: u32,
+    chunks: HashMap<[u8; 50], DataChunk>,
+    // (hash, offset, units) in write order
+    pending: Vec<([u8; 50], u32, u32)>,
+    done: bool,
+}
+
+impl DataLake {
+    pub fn begin(&self) -> UssResult<Transaction<'_>> {
+        if self.readonly {
+            return Err(UssError::StaticError(
+                "Must not call begin() on readonly lake.",
+            ));
+        }
+
+        let writer = self.lock_appends()?;
+        let start = self.data_next();
+
+        *self
+            .transaction_thread
+            .lock()
+            .map_err(|_| UssError::MutexPoison)? = Some(std::thread::current().id());
+
+        Ok(Transaction {
+            lake: self,
+            _writer: writer,
+            start,
+            next: start,
+            chunks: HashMap::new(),
+            pending: Vec::new(),
+            done: false,
+        })
+    }
+
+    // A crash during commit() can leave index entries pointing past
+    // data_next, at chunks that were never published. A writer opening the
+    // lake removes them, and zeroes the transaction's chunks so repair()
+    // doesn't revive part of it. Returns the number of entries removed.
+    pub(super) fn discard_unpublished(&self) -> UssResult<usize> {
+        let data_next = self.data_next();
+        let end = self.mapped_units();
+
+        if data_next < self.header().data_offset || data_next > end {
+            // a damaged header, which is for repair() to sort out
+            return Ok(0);
+        }
+
+        let slots: Vec<u32> = self
+            .index_entries()
+            .into_iter()
+            .filter(|(_, offset)| *offset >= data_next)
+            .map(|(slot, _)| slot)
+            .collect();
+
+        if slots.is_empty() {
+            return Ok(0);
+        }
+
+        let mut map = self.lock_map()?;
+
+        // they are the latest inserts, so no other probe chain runs through them
+        for slot in slots.iter() {
+            self.index_remove(&mut map, *slot);
+        }
+
+        if self.index_format() == IndexFormat::Bucketed {
+            // the crash may have come between a slot and its count
+            let used = self.index_entries().len() as u32;
+
+            self.header().index_used.store(used, Ordering::Release);
+        }
+
+        // the transaction wrote its chunks back to back from data_next
+        let mut offset = data_next;
+
+        while offset < end {
+            let header = read_header(&self.data, offset);
+
+            if header.hash[0] == 0 || !crate::hasher::verify_hash_integrity(&header.hash) {
+                break;
+            }
+
+            offset = std::cmp::min(offset + header.units(), end);
+        }
+
+        map[offset_to_data_offset(data_next)..offset_to_data_offset(offset)].fill(0);
+
+        self.flush_index(&map)?;
+        map.flush().map_err(to_error)?;
+
+        Ok(slots.len())
+    }
+}
+
+impl<'a> Transaction<'a> {
+    pub fn put(&mut self, data: &[u8]) -> UssResult<DataChunk> {
+        let hash = self.lake.hash_of(data);
+
+        if let Some(chunk) = self.get(&hash) {
+            return Ok(chunk);
+        }
+
+        let (header, payload) = self.lake.encode_chunk(hash, data)?;
+
+        self.write(header, &payload)
+    }
+
+    // like DataLake::put_payload()
+    pub fn put_payload(
+        &mut self,
+        hash: &[u8; 50],
+        uncompressed_length: u16,
+        payload: &[u8],
+    ) -> UssResult<DataChunk> {
+        if let Some(chunk) = self.get(hash) {
+            return Ok(chunk);
+        }
+
+        let (header, payload) = self
+            .lake
+            .encode_portable(hash, uncompressed_length, payload)?;
+
+        self.write(header, &payload)
+    }
+
+    // a chunk put in this transaction, or already stored in the lake
+    pub fn get(&self, hash: &[u8; 50]) -> Option<DataChunk> {
+        match self.chunks.get(hash) {
+            Some(chunk) => Some(chunk.clone()),
+            None => self.lake.get(hash),
+        }
+    }
+
+    fn write(&mut self, header: DataChunkHeader, payload: &[u8]) -> UssResult<DataChunk> {
+        let hash = header.hash;
+        let mut map = self.lake.lock_map()?;
+        let units = self
+            .lake
+            .write_chunk(&mut map, self.next, &header, payload)?;
+        let chunk = self.lake.written_chunk(header, self.next);
+
+        self.chunks.insert(hash, chunk.clone());
+        self.pending.push((hash, self.next, units));
+        self.next += units;
+
+        Ok(chunk)
+    }
+
+    // chunks written so far
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    // Chunks and index entries are flushed first; the entries point past
+    // data_next, so readers ignore them until data_next is advanced over all
+    // chunks at once, and flushed last.
+    pub fn commit(mut self) -> UssResult<()> {
+        self.done = true;
+
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+
+        let mut map = self.lake.lock_map()?;
+        let start_bytes = offset_to_data_offset(self.start);
+        let length = offset_to_data_offset(self.next) - start_bytes;
+        let mut slots = Vec::with_capacity(self.pending.len());
+
+        let result = map
+            .flush_range(start_bytes, length)
+            .map_err(to_error)
+            .and_then(|_| {
+                for (hash, offset, _) in self.pending.iter() {
+                    slots.push(self.lake.index_insert(&mut map, hash, *offset)?);
+                }
+
+                self.lake.flush_index(&map)
+            });
+
+        if let Err(err) = result {
+            for slot in slots.iter().rev() {
+                self.lake.index_remove(&mut map, *slot);
+            }
+
+            map[start_bytes..start_bytes + length].fill(0);
+
+            return Err(err);
+        }
+
+        self.lake
+            .header()
+            .data_next
+            .store(self.next, Ordering::Release);
+
+        for (hash, offset, units) in self.pending.iter() {
+            self.lake.filter_insert(hash, *offset, *units);
+        }
+
+        map.flush_range(0, 256).map_err(to_error)
+    }
+
+    // discards the written chunks; data_next was never moved
+    pub fn abort(mut self) {
+        self.rollback();
+    }
+
+    fn rollback(&mut self) {
+        self.done = true;
+
+        if self.pending.is_empty() {
+            return;
+        }
+
+        // zeroed so repair(), which scans past data_next, doesn't revive them
+        if let Ok(mut map) = self.lake.lock_map() {
+            let start_bytes = offset_to_data_offset(self.start);
+            let end_bytes = offset_to_data_offset(self.next);
+
+            map[start_bytes..end_bytes].fill(0);
+        }
+    }
+}
+
+impl<'a> Drop for Transaction<'a> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.rollback();
+        }
+
+        if let Ok(mut thread) = self.lake.transaction_thread.lock() {
+            *thread = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commit_publishes_all_chunks() {
+        let path = test_path("transaction.lake");
+        let lake = DataLake::create(&path, 1 << 20).unwrap();
+        let mut transaction = lake.begin().unwrap();
+        let hashes: Vec<[u8; 50]> = (0..10)
+            .map(|i| {
+                transaction
+                    .put(format!("chunk {}", i).as_bytes())
+                    .unwrap()
+                    .header
+                    .hash
+            })
+            .collect();
+
+        assert!(hashes.iter().all(|hash| lake.get(hash).is_none()));
+
+        transaction.commit().unwrap();
+        drop(lake);
+
+        let lake = DataLake::load(&path, true).unwrap();
+
+        assert!(hashes.iter().all(|hash| lake.get(hash).is_some()));
+    }
+
+    #[test]
+    fn abort_leaves_nothing() {
+        let path = test_path("transaction-abort.lake");
+        let mut lake = DataLake::create(&path, 1 << 20).unwrap();
+        let mut transaction = lake.begin().unwrap();
+        let hash = transaction.put(b"aborted").unwrap().header.hash;
+
+        transaction.abort();
+
+        assert!(lake.get(&hash).is_none());
+        assert_eq!(lake.repair().unwrap().chunks_indexed, 0);
+    }
+
+    #[test]
+    fn failed_commit_rolls_back_the_index() {
+        let path = test_path("transaction-full.lake");
+        let lake = DataLake::create(&path, 1 << 20).unwrap();
+        let kept = lake.put(b"stored before").unwrap().header.hash;
+        let used = lake.header().index_used.load(Ordering::Acquire);
+        let entries = lake.index_entries();
+        let mut transaction = lake.begin().unwrap();
+
+        // more chunks than the index takes, so commit() fails midway
+        for i in 0..lake.index_capacity() {
+            transaction.put(format!("chunk {}", i).as_bytes()).unwrap();
+        }
+
+        assert!(transaction.commit().is_err());
+        assert_eq!(lake.header().index_used.load(Ordering::Acquire), used);
+        assert_eq!(lake.index_entries(), entries);
+        assert!(lake.get(&kept).is_some());
+        assert!(lake.get(&crate::hasher::hash(b"chunk 0")).is_none());
+        assert!(lake.fsck().unwrap().is_clean());
+    }
+
+    #[test]
+    fn put_on_the_transaction_thread_fails() {
+        let path = test_path("transaction-same-thread.lake");
+        let lake = DataLake::create(&path, 1 << 20).unwrap();
+        let mut transaction = lake.begin().unwrap();
+
+        transaction.put(b"in the transaction").unwrap();
+
+        assert!(lake.put(b"beside the transaction").is_err());
+        assert!(lake.put_many(&[b"beside the transaction"]).is_err());
+        assert!(lake.begin().is_err());
+
+        transaction.commit().unwrap();
+
+        assert!(lake.put(b"after the transaction").is_ok());
+        assert!(lake.begin().is_ok());
+    }
+
+    #[test]
+    fn crashed_commit_is_discarded_on_open() {
+        let path = test_path("transaction-crash.lake");
+        let hashes: Vec<[u8; 50]>;
+
+        {
+            let lake = DataLake::create(&path, 1 << 20).unwrap();
+
+            lake.put(b"published before the crash").unwrap();
+
+            let mut transaction = lake.begin().unwrap();
+
+            for i in 0..10 {
+                transaction.put(format!("chunk {}", i).as_bytes()).unwrap();
+            }
+
+            hashes = transaction
+                .pending
+                .iter()
+                .map(|(hash, _, _)| *hash)
+                .collect();
+
+            // the crash: half the entries inserted, data_next never advanced
+            let mut map = lake.lock_map().unwrap();
+
+            for (hash, offset, _) in transaction.pending[..5].iter() {
+                lake.index_insert(&mut map, hash, *offset).unwrap();
+            }
+
+            lake.header().index_used.fetch_add(1, Ordering::AcqRel);
+
+            drop(map);
+            std::mem::forget(transaction);
+        }
+
+        let report = DataLake::load(&path, true).unwrap().fsck().unwrap();
+
+        assert_eq!(report.unpublished_slots.len(), 5);
+        assert_eq!(report.header_errors.len(), 1);
+
+        let mut lake = DataLake::load(&path, false).unwrap();
+
+        assert!(lake.fsck().unwrap().is_clean());
+        assert_eq!(lake.header().index_used.load(Ordering::Acquire), 1);
+        assert!(hashes.iter().all(|hash| lake.get(hash).is_none()));
+        assert_eq!(lake.repair().unwrap().chunks_indexed, 1);
+    }
+}
@@ -1,13 +1,10 @@
 use crate::{
     serializer::{deserialize, serialize},
-    store::DataLake,
+    store::ChunkStore,
     *,
 };
 use serde::{de::DeserializeOwned, Serialize};
-use std::{
-    rc::Rc,
-    sync::{Arc, Mutex},
-};
+use std::{rc::Rc, sync::Arc};
 
 #[derive(Clone)]
 pub struct Leaf<K, V> {
@@ -17,7 +14,7 @@ pub struct Leaf<K, V> {
     val_u32: u32,
     val_ref: String,
     val_val: Option<Rc<V>>,
-    lake: Arc<Mutex<DataLake>>,
+    lake: Arc<dyn ChunkStore>,
 }
 
 impl<K, V> Leaf<K, V>
@@ -25,14 +22,11 @@ where
     K: Serialize + DeserializeOwned,
     V: Serialize + DeserializeOwned,
 {
-    pub fn from_hash(hash: &[u8], lake: Arc<Mutex<DataLake>>) -> UssResult<Self> {
-        let mut lock = lake.lock().map_err(to_error)?;
-        let (key_ref, val_ref) = deserialize::<(String, String)>(hash, &mut lock)?;
+    pub fn from_hash(hash: &[u8], lake: Arc<dyn ChunkStore>) -> UssResult<Self> {
+        let (key_ref, val_ref) = deserialize::<(String, String), _>(hash, &lake)?;
         let key_u32 = Self::hash_to_u32(key_ref.as_bytes());
         let val_u32 = Self::hash_to_u32(val_ref.as_bytes());
 
-        drop(lock);
-
         Ok(Self {
             key_u32,
             key_ref,
@@ -44,17 +38,13 @@ where
         })
     }
 
-    pub fn from_kvrc(key: Rc<K>, val: Rc<V>, lake: Arc<Mutex<DataLake>>) -> UssResult<Self> {
-        let mut lock = lake.lock().map_err(to_error)?;
-
-        let key_ref = serialize(key.as_ref(), &mut lock)?;
-        let val_ref = serialize(val.as_ref(), &mut lock)?;
+    pub fn from_kvrc(key: Rc<K>, val: Rc<V>, lake: Arc<dyn ChunkStore>) -> UssResult<Self> {
+        let key_ref = serialize(key.as_ref(), &lake)?;
+        let val_ref = serialize(val.as_ref(), &lake)?;
 
         let key_u32 = Self::hash_to_u32(key_ref.as_bytes());
         let val_u32 = Self::hash_to_u32(val_ref.as_bytes());
 
-        drop(lock);
-
         Ok(Self {
             key_u32,
             key_ref,
@@ -66,14 +56,12 @@ where
         })
     }
 
-    pub fn from_kv(key: K, value: V, lake: Arc<Mutex<DataLake>>) -> UssResult<Self> {
+    pub fn from_kv(key: K, value: V, lake: Arc<dyn ChunkStore>) -> UssResult<Self> {
         Self::from_kvrc(Rc::from(key), Rc::from(value), lake)
     }
 
     pub fn hash(&self) -> UssResult<String> {
-        let mut lock = self.lake.lock().map_err(to_error)?;
-
-        serialize(&(self.key_ref.as_str(), self.val_ref.as_str()), &mut lock)
+        serialize(&(self.key_ref.as_str(), self.val_ref.as_str()), &self.lake)
     }
 
     pub fn hash_to_u32(bytes: &[u8]) -> u32 {
@@ -91,8 +79,7 @@ where
             return Ok(key);
         }
 
-        let mut lock = self.lake.lock().map_err(to_error)?;
-        let key: K = deserialize(self.key_ref.as_bytes(), &mut lock)?;
+        let key: K = deserialize(self.key_ref.as_bytes(), &self.lake)?;
         let rc = Rc::new(key);
 
         return Ok(rc);
@@ -111,8 +98,7 @@ where
             return Ok(value);
         }
 
-        let mut lock = self.lake.lock().map_err(to_error)?;
-        let value: V = deserialize(self.val_ref.as_bytes(), &mut lock)?;
+        let value: V = deserialize(self.val_ref.as_bytes(), &self.lake)?;
         let rc = Rc::new(value);
 
         return Ok(rc);
@@ -127,13 +113,9 @@ where
     }
 
     pub fn set_rc(&mut self, value: Rc<V>) -> UssResult<&mut Self> {
-        let mut lock = self.lake.lock().map_err(to_error)?;
-
-        let val_ref = serialize(value.as_ref(), &mut lock)?;
+        let val_ref = serialize(value.as_ref(), &self.lake)?;
         let val_u32 = Self::hash_to_u32(val_ref.as_bytes());
 
-        drop(lock);
-
         self.val_ref = val_ref;
         self.val_u32 = val_u32;
         self.val_val = Some(value);
@@ -169,3 +151,46 @@ where
         self.val_val.clone()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    #[test]
+    fn round_trip() {
+        let lake: Arc<dyn ChunkStore> = Arc::new(MemoryStore::new());
+        let long_value = "a value long enough to be stored as a chunk".repeat(3);
+        let leaf = Leaf::from_kv(String::from("key"), long_value.clone(), lake.clone()).unwrap();
+        let hash = leaf.hash().unwrap();
+
+        let loaded = Leaf::<String, String>::from_hash(hash.as_bytes(), lake).unwrap();
+
+        assert_eq!(loaded.key_u32(), leaf.key_u32());
+        assert_eq!(loaded.val_u32(), leaf.val_u32());
+        assert!(loaded.key_val().is_none());
+        assert_eq!(*loaded.key().unwrap(), "key");
+        assert_eq!(*loaded.value().unwrap(), long_value);
+    }
+
+    #[test]
+    fn set_replaces_the_value() {
+        let lake: Arc<dyn ChunkStore> = Arc::new(MemoryStore::new());
+        let mut leaf =
+            Leaf::from_kv(String::from("key"), String::from("first"), lake.clone()).unwrap();
+        let before = leaf.hash().unwrap();
+
+        leaf.set(String::from("second")).unwrap();
+
+        let after = leaf.hash().unwrap();
+
+        assert_ne!(before, after);
+        assert_eq!(
+            *Leaf::<String, String>::from_hash(after.as_bytes(), lake)
+                .unwrap()
+                .value()
+                .unwrap(),
+            "second"
+        );
+    }
+}
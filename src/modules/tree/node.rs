@@ -1,11 +1,7 @@
 use super::*;
-use crate::{hasher::checksum_u32, serializer::*, store::DataLake, *};
+use crate::{hasher::checksum_u32, serializer::*, store::ChunkStore, *};
 use serde::{de::DeserializeOwned, Serialize};
-use std::{
-    cell::Cell,
-    rc::Rc,
-    sync::{Arc, Mutex},
-};
+use std::{cell::Cell, rc::Rc, sync::Arc};
 
 pub enum NodeChild<K, V> {
     Node(Rc<Node<K, V>>),
@@ -141,7 +137,7 @@ where
 pub struct Node<K, V> {
     depth: usize,
     entries: Vec<NodeEntry<K, V>>,
-    lake: Arc<Mutex<DataLake>>,
+    lake: Arc<dyn ChunkStore>,
 }
 
 impl<K, V> Clone for Node<K, V>
@@ -164,7 +160,7 @@ where
     V: Serialize + DeserializeOwned,
 {
     pub fn new_from_props(
-        lake: Arc<Mutex<DataLake>>,
+        lake: Arc<dyn ChunkStore>,
         depth: usize,
         entries: Vec<NodeEntry<K, V>>,
     ) -> Self {
@@ -175,11 +171,11 @@ where
         }
     }
 
-    pub fn new_with_depth(lake: Arc<Mutex<DataLake>>, depth: usize) -> Self {
+    pub fn new_with_depth(lake: Arc<dyn ChunkStore>, depth: usize) -> Self {
         Self::new_from_props(lake, depth, vec![])
     }
 
-    pub fn new(lake: Arc<Mutex<DataLake>>) -> Self {
+    pub fn new(lake: Arc<dyn ChunkStore>) -> Self {
         Self::new_with_depth(lake, 0)
     }
 
@@ -213,9 +209,7 @@ where
                 }
             }
 
-            let mut lock = self.lake.lock().map_err(to_error)?;
-
-            serialize(&(self.depth, children), &mut lock)
+            serialize(&(self.depth, children), &self.lake)
         } else {
             let mut children: Vec<(u32, Rc<String>)> = Vec::new();
 
@@ -237,15 +231,12 @@ where
                     .collect::<Vec<(u32, &str)>>(),
             );
 
-            let mut lock = self.lake.lock().map_err(to_error)?;
-
-            serialize(&arg, &mut lock)
+            serialize(&arg, &self.lake)
         }
     }
 
-    pub fn from_hash(hash: &[u8], lake: Arc<Mutex<DataLake>>) -> UssResult<Self> {
-        let mut lock = lake.lock().map_err(to_error)?;
-        let (depth, children) = deserialize::<(usize, Vec<(u32, String)>)>(hash, &mut lock)?;
+    pub fn from_hash(hash: &[u8], lake: Arc<dyn ChunkStore>) -> UssResult<Self> {
+        let (depth, children) = deserialize::<(usize, Vec<(u32, String)>), _>(hash, &lake)?;
 
         let mut entries: Vec<NodeEntry<K, V>> = Vec::with_capacity(children.len());
 
@@ -272,8 +263,6 @@ where
             }
         }
 
-        drop(lock);
-
         Ok(Node {
             depth,
             entries,
@@ -438,8 +427,7 @@ where
     }
 
     pub fn key_to_u32(&self, key: &K) -> UssResult<u32> {
-        let mut lock = self.lake.lock().map_err(to_error)?;
-        let hash = serializer::serialize(key, &mut lock)?;
+        let hash = serializer::serialize(key, &self.lake)?;
         let key = checksum_u32(hash.as_bytes(), hash.len() as u32);
 
         Ok(key)
@@ -457,7 +445,7 @@ where
 pub enum LazyContent<K, V> {
     None,
     Node(Rc<Node<K, V>>),
-    Lake(Arc<Mutex<DataLake>>),
+    Lake(Arc<dyn ChunkStore>),
 }
 
 pub struct Lazy<K, V> {
@@ -489,14 +477,14 @@ where
         self.hash.clone()
     }
 
-    pub fn from_rc_hash(hash: Rc<String>, lake: Arc<Mutex<DataLake>>) -> Self {
+    pub fn from_rc_hash(hash: Rc<String>, lake: Arc<dyn ChunkStore>) -> Self {
         Self {
             content: Cell::from(LazyContent::Lake(lake)),
             hash,
         }
     }
 
-    pub fn from_hash(hash: String, lake: Arc<Mutex<DataLake>>) -> Self {
+    pub fn from_hash(hash: String, lake: Arc<dyn ChunkStore>) -> Self {
         Self::from_rc_hash(Rc::from(hash), lake)
     }
 }
@@ -562,3 +550,60 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    #[test]
+    fn round_trip() {
+        let lake: Arc<dyn ChunkStore> = Arc::new(MemoryStore::new());
+        let mut node = Node::<String, String>::new(lake.clone());
+
+        // leaves are keyed by the first bytes of the key's ref, so keys differ there
+        for i in 0..8 {
+            node = node
+                .set(format!("{} key", i), format!("value {}", i))
+                .unwrap();
+        }
+
+        // setting a key again replaces its leaf
+        node = node
+            .set(String::from("3 key"), String::from("replaced"))
+            .unwrap();
+
+        let hash = node.hash().unwrap();
+        let loaded = Node::<String, String>::from_hash(hash.as_bytes(), lake.clone()).unwrap();
+
+        assert_eq!(loaded.hash().unwrap(), hash);
+
+        for i in 0..8 {
+            let key = format!("{} key", i);
+            let leaf = Leaf::from_kv(key.clone(), String::from("unused"), lake.clone()).unwrap();
+            let found = loaded.get_by_u32(leaf.key_u32()).unwrap().unwrap();
+
+            let value = match i {
+                3 => String::from("replaced"),
+                _ => format!("value {}", i),
+            };
+
+            assert_eq!(*found.key().unwrap(), key);
+            assert_eq!(*found.value().unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn missing_chunks_fail_to_load() {
+        let lake: Arc<dyn ChunkStore> = Arc::new(MemoryStore::new());
+        let node = Node::<String, String>::new(lake)
+            .set(String::from("key"), "value".repeat(20))
+            .unwrap();
+        let hash = node.hash().unwrap();
+
+        assert!(
+            Node::<String, String>::from_hash(hash.as_bytes(), Arc::new(MemoryStore::new()))
+                .is_err()
+        );
+    }
+}